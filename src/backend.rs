@@ -0,0 +1,971 @@
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::{AsyncBufReadExt, Stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, sqlx::Type)]
+#[sqlx(rename_all = "lowercase")]
+pub(crate) enum BackendKind {
+    #[default]
+    Ollama,
+    #[sqlx(rename = "openai")]
+    OpenAi,
+}
+
+impl BackendKind {
+    pub(crate) fn connect(self, http_client: reqwest::Client, url: &str) -> Arc<dyn ChatBackend> {
+        let url = url.trim_end_matches('/').to_string();
+
+        match self {
+            BackendKind::Ollama => Arc::new(OllamaBackend { http_client, url }),
+            BackendKind::OpenAi => Arc::new(OpenAiBackend { http_client, url }),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct BackendModel {
+    pub(crate) name: String,
+    pub(crate) size: Option<i64>,
+}
+
+pub(crate) trait ChatBackend: Send + Sync + std::fmt::Debug {
+    fn models(&self) -> BoxFuture<'_, anyhow::Result<Vec<BackendModel>>>;
+
+    fn chat<'a>(
+        &'a self,
+        request: &'a ChatRequest,
+    ) -> BoxFuture<'a, anyhow::Result<BoxStream<'static, anyhow::Result<ChatResponse>>>>;
+
+    fn complete<'a>(&'a self, request: &'a ChatRequest) -> BoxFuture<'a, anyhow::Result<String>> {
+        Box::pin(async move {
+            let mut chunks = self.chat(request).await?;
+
+            let mut think_tags = ThinkTags::default();
+            let mut content = String::new();
+
+            while let Some(chunk) = chunks.next().await {
+                let chunk = chunk?;
+
+                content.push_str(&think_tags.push(&chunk.message.content).1);
+
+                if chunk.done {
+                    content.push_str(&think_tags.finish().1);
+                    return Ok(content);
+                }
+            }
+
+            anyhow::bail!("the backend stopped responding before the reply was done")
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct OllamaBackend {
+    http_client: reqwest::Client,
+    url: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct OllamaModelsResponse {
+    models: Vec<OllamaModelResponse>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OllamaModelResponse {
+    name: String,
+    size: Option<i64>,
+}
+
+impl ChatBackend for OllamaBackend {
+    fn models(&self) -> BoxFuture<'_, anyhow::Result<Vec<BackendModel>>> {
+        Box::pin(async move {
+            let models: OllamaModelsResponse = self
+                .http_client
+                .get(format!("{}/api/tags", self.url))
+                .timeout(std::time::Duration::from_secs(5))
+                .send()
+                .await?
+                .json()
+                .await?;
+
+            Ok(models
+                .models
+                .into_iter()
+                .map(|model| BackendModel {
+                    name: model.name,
+                    size: model.size,
+                })
+                .collect())
+        })
+    }
+
+    fn chat<'a>(
+        &'a self,
+        request: &'a ChatRequest,
+    ) -> BoxFuture<'a, anyhow::Result<BoxStream<'static, anyhow::Result<ChatResponse>>>> {
+        Box::pin(async move {
+            let resp = self
+                .http_client
+                .post(format!("{}/api/chat", self.url))
+                .json(request)
+                .send()
+                .await
+                .map_err(|e| anyhow::anyhow!("could not reach Ollama: {e}"))?;
+
+            let lines = ndjson_lines(resp).await?;
+
+            let chunks = lines.map(|line| {
+                let line =
+                    line.map_err(|e| anyhow::anyhow!("error receiving ndjson stream: {e}"))?;
+
+                serde_json::from_str::<ChatResponse>(&line).map_err(|e| {
+                    // Ollama reports errors that happen mid-generation in-band
+                    match serde_json::from_str::<OllamaErrorResponse>(&line) {
+                        Ok(error_response) => {
+                            anyhow::anyhow!("Ollama returned an error: {}", error_response.error)
+                        }
+                        Err(_) => {
+                            anyhow::anyhow!("could not understand Ollama's response ({e}): {line}")
+                        }
+                    }
+                })
+            });
+
+            Ok(chunks.boxed())
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct OpenAiBackend {
+    http_client: reqwest::Client,
+    url: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAiModelsResponse {
+    data: Vec<OpenAiModel>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAiModel {
+    id: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAiErrorResponse {
+    error: OpenAiError,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum OpenAiError {
+    Message { message: String },
+    Text(String),
+}
+
+impl OpenAiError {
+    fn message(self) -> String {
+        match self {
+            OpenAiError::Message { message } | OpenAiError::Text(message) => message,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAiChunk {
+    model: Option<String>,
+    #[serde(default)]
+    choices: Vec<OpenAiChoice>,
+    usage: Option<OpenAiUsage>,
+    timings: Option<LlamaCppTimings>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAiChoice {
+    #[serde(default)]
+    delta: OpenAiDelta,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct OpenAiDelta {
+    content: Option<String>,
+    reasoning_content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<OpenAiToolCallDelta>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAiToolCallDelta {
+    #[serde(default)]
+    index: usize,
+    function: Option<OpenAiFunctionDelta>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAiFunctionDelta {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAiUsage {
+    prompt_tokens: Option<i64>,
+    completion_tokens: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct LlamaCppTimings {
+    prompt_ms: Option<f64>,
+    predicted_ms: Option<f64>,
+}
+
+#[derive(Default)]
+struct OpenAiReply {
+    tool_calls: BTreeMap<usize, (String, String)>,
+    metadata: GenerationMetadata,
+}
+
+impl OpenAiReply {
+    fn push(&mut self, chunk: OpenAiChunk) -> Option<ChatResponse> {
+        if chunk.model.is_some() {
+            self.metadata.model = chunk.model;
+        }
+
+        if let Some(usage) = chunk.usage {
+            self.metadata.prompt_eval_count = usage.prompt_tokens;
+            self.metadata.eval_count = usage.completion_tokens;
+        }
+
+        if let Some(timings) = chunk.timings {
+            let nanos = |ms: Option<f64>| ms.map(|ms| (ms * 1_000_000.0) as i64);
+
+            self.metadata.prompt_eval_duration = nanos(timings.prompt_ms);
+            self.metadata.eval_duration = nanos(timings.predicted_ms);
+        }
+
+        let choice = chunk.choices.into_iter().next()?;
+
+        if choice.finish_reason.is_some() {
+            self.metadata.done_reason = choice.finish_reason;
+        }
+
+        for tool_call in choice.delta.tool_calls {
+            let (name, arguments) = self.tool_calls.entry(tool_call.index).or_default();
+
+            if let Some(function) = tool_call.function {
+                name.push_str(&function.name.unwrap_or_default());
+                arguments.push_str(&function.arguments.unwrap_or_default());
+            }
+        }
+
+        let content = choice.delta.content.unwrap_or_default();
+        let thinking = choice.delta.reasoning_content.unwrap_or_default();
+
+        if content.is_empty() && thinking.is_empty() {
+            return None;
+        }
+
+        Some(ChatResponse {
+            message: ChatResponseMessage {
+                content,
+                thinking: Some(thinking),
+                tool_calls: vec![],
+            },
+            done: false,
+            metadata: GenerationMetadata::default(),
+        })
+    }
+
+    fn finish(self) -> anyhow::Result<ChatResponse> {
+        let tool_calls = self
+            .tool_calls
+            .into_values()
+            .map(|(name, arguments)| {
+                let arguments = if arguments.trim().is_empty() {
+                    json!({})
+                } else {
+                    serde_json::from_str(&arguments).map_err(|e| {
+                        anyhow::anyhow!(
+                            "the model called {name} with arguments that aren't JSON: {e}"
+                        )
+                    })?
+                };
+
+                anyhow::Ok(ToolCall {
+                    function: ToolCallFunction { name, arguments },
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(ChatResponse {
+            message: ChatResponseMessage {
+                content: String::new(),
+                thinking: None,
+                tool_calls,
+            },
+            done: true,
+            metadata: self.metadata,
+        })
+    }
+}
+
+fn image_type(base64: &str) -> &'static str {
+    match base64.get(..4) {
+        Some("/9j/") => "image/jpeg",
+        Some("R0lG") => "image/gif",
+        Some("UklG") => "image/webp",
+        _ => "image/png",
+    }
+}
+
+fn openai_request(request: &ChatRequest) -> serde_json::Value {
+    let mut tool_call_ids = 0;
+
+    let messages: Vec<serde_json::Value> = request
+        .messages
+        .iter()
+        .map(|message| {
+            let content = if message.images.is_empty() {
+                json!(message.content)
+            } else {
+                let text = json!({ "type": "text", "text": message.content });
+
+                let images = message.images.iter().map(|image| {
+                    json!({
+                        "type": "image_url",
+                        "image_url": { "url": format!("data:{};base64,{image}", image_type(image)) }
+                    })
+                });
+
+                std::iter::once(text).chain(images).collect()
+            };
+
+            let mut openai_message = json!({ "role": message.role, "content": content });
+
+            // OpenAI ties tool results to their calls by id, where Ollama goes by order.
+            // a tool's result always comes right after its call
+            if !message.tool_calls.is_empty() {
+                openai_message["tool_calls"] = message
+                    .tool_calls
+                    .iter()
+                    .map(|tool_call| {
+                        tool_call_ids += 1;
+
+                        json!({
+                            "id": format!("call_{tool_call_ids}"),
+                            "type": "function",
+                            "function": {
+                                "name": tool_call.function.name,
+                                "arguments": tool_call.function.arguments.to_string(),
+                            }
+                        })
+                    })
+                    .collect();
+            } else if message.role == "tool" {
+                openai_message["tool_call_id"] = json!(format!("call_{tool_call_ids}"));
+            }
+
+            openai_message
+        })
+        .collect();
+
+    let mut body = json!({
+        "model": request.model,
+        "messages": messages,
+        "stream": true,
+        "stream_options": { "include_usage": true },
+    });
+
+    let options = &request.options;
+
+    // there's no num_ctx: these servers fix their context length when they start.
+    // it still decides how much of the conversation we send
+    for (name, value) in [
+        ("temperature", options.temperature.map(|v| json!(v))),
+        ("top_p", options.top_p.map(|v| json!(v))),
+        ("top_k", options.top_k.map(|v| json!(v))),
+        ("seed", options.seed.map(|v| json!(v))),
+        ("repeat_penalty", options.repeat_penalty.map(|v| json!(v))),
+        ("max_tokens", options.num_predict.map(|v| json!(v))),
+    ] {
+        if let Some(value) = value {
+            body[name] = value;
+        }
+    }
+
+    if let Some(stop) = &options.stop {
+        body["stop"] = stop.lines().filter(|line| !line.is_empty()).collect();
+    }
+
+    if !request.tools.is_empty() {
+        body["tools"] = json!(request.tools);
+    }
+
+    match &request.format {
+        Some(format) if format.is_string() => {
+            body["response_format"] = json!({ "type": "json_object" });
+        }
+        Some(schema) => {
+            body["response_format"] = json!({
+                "type": "json_schema",
+                "json_schema": { "name": "reply", "schema": schema },
+            });
+        }
+        None => {}
+    }
+
+    body
+}
+
+impl OpenAiBackend {
+    async fn error(resp: reqwest::Response) -> anyhow::Error {
+        let status = resp.status();
+
+        let error = match resp.json::<OpenAiErrorResponse>().await {
+            Ok(error_response) => error_response.error.message(),
+            Err(_) => status.to_string(),
+        };
+
+        anyhow::anyhow!("the backend returned an error: {error}")
+    }
+}
+
+impl ChatBackend for OpenAiBackend {
+    fn models(&self) -> BoxFuture<'_, anyhow::Result<Vec<BackendModel>>> {
+        Box::pin(async move {
+            let resp = self
+                .http_client
+                .get(format!("{}/v1/models", self.url))
+                .timeout(std::time::Duration::from_secs(5))
+                .send()
+                .await?;
+
+            if !resp.status().is_success() {
+                return Err(Self::error(resp).await);
+            }
+
+            let models: OpenAiModelsResponse = resp.json().await?;
+
+            Ok(models
+                .data
+                .into_iter()
+                .map(|model| BackendModel {
+                    name: model.id,
+                    size: None,
+                })
+                .collect())
+        })
+    }
+
+    fn chat<'a>(
+        &'a self,
+        request: &'a ChatRequest,
+    ) -> BoxFuture<'a, anyhow::Result<BoxStream<'static, anyhow::Result<ChatResponse>>>> {
+        Box::pin(async move {
+            let resp = self
+                .http_client
+                .post(format!("{}/v1/chat/completions", self.url))
+                .json(&openai_request(request))
+                .send()
+                .await
+                .map_err(|e| anyhow::anyhow!("could not reach {}: {e}", self.url))?;
+
+            if !resp.status().is_success() {
+                return Err(Self::error(resp).await);
+            }
+
+            Ok(openai_chunks(ndjson_lines(resp).await?))
+        })
+    }
+}
+
+fn openai_chunks(
+    lines: impl Stream<Item = std::io::Result<String>> + Send + 'static,
+) -> BoxStream<'static, anyhow::Result<ChatResponse>> {
+    futures::stream::try_unfold(
+        Some((lines.boxed(), OpenAiReply::default())),
+        |state| async move {
+            let Some((mut lines, mut reply)) = state else {
+                return Ok(None);
+            };
+
+            while let Some(line) = lines.next().await {
+                let line =
+                    line.map_err(|e| anyhow::anyhow!("error receiving event stream: {e}"))?;
+
+                let Some(data) = line.strip_prefix("data:") else {
+                    continue;
+                };
+
+                let data = data.trim();
+
+                if data == "[DONE]" {
+                    return Ok(Some((reply.finish()?, None)));
+                }
+
+                // checked first, since every field of a chunk is optional
+                if let Ok(error_response) = serde_json::from_str::<OpenAiErrorResponse>(data) {
+                    anyhow::bail!(
+                        "the backend returned an error: {}",
+                        error_response.error.message()
+                    );
+                }
+
+                let chunk: OpenAiChunk = serde_json::from_str(data).map_err(|e| {
+                    anyhow::anyhow!("could not understand the backend's response ({e}): {data}")
+                })?;
+
+                if let Some(chat_response) = reply.push(chunk) {
+                    return Ok(Some((chat_response, Some((lines, reply)))));
+                }
+            }
+
+            Ok(None)
+        },
+    )
+    .boxed()
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct ChatRequest {
+    pub(crate) model: String,
+    pub(crate) messages: Vec<ChatRequestMessage>,
+    pub(crate) options: GenerationOptions,
+    #[serde(
+        serialize_with = "serialize_keep_alive",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) keep_alive: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) tools: Vec<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) format: Option<serde_json::Value>,
+}
+
+fn serialize_keep_alive<S>(keep_alive: &Option<String>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match keep_alive
+        .as_deref()
+        .map(|keep_alive| keep_alive.parse::<i64>())
+    {
+        Some(Ok(seconds)) => serializer.serialize_i64(seconds),
+        _ => keep_alive.serialize(serializer),
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, sqlx::FromRow)]
+pub(crate) struct GenerationOptions {
+    #[serde(
+        default,
+        deserialize_with = "empty_string_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) temperature: Option<f64>,
+    #[serde(
+        default,
+        deserialize_with = "empty_string_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) top_p: Option<f64>,
+    #[serde(
+        default,
+        deserialize_with = "empty_string_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) top_k: Option<i64>,
+    #[serde(
+        default,
+        deserialize_with = "empty_string_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) num_ctx: Option<i64>,
+    #[serde(
+        default,
+        deserialize_with = "empty_string_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) seed: Option<i64>,
+    #[serde(
+        default,
+        deserialize_with = "empty_string_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) repeat_penalty: Option<f64>,
+    // stored as one stop sequence per line
+    #[serde(
+        default,
+        deserialize_with = "empty_string_as_none",
+        serialize_with = "serialize_stop_sequences",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) stop: Option<String>,
+    #[serde(
+        default,
+        deserialize_with = "empty_string_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) num_predict: Option<i64>,
+}
+
+fn empty_string_as_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let s = String::deserialize(deserializer)?;
+    let s = s.trim();
+
+    if s.is_empty() {
+        Ok(None)
+    } else {
+        s.parse().map(Some).map_err(serde::de::Error::custom)
+    }
+}
+
+fn serialize_stop_sequences<S>(stop: &Option<String>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    let stop: Vec<&str> = stop
+        .iter()
+        .flat_map(|stop| stop.lines())
+        .filter(|line| !line.is_empty())
+        .collect();
+
+    stop.serialize(serializer)
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct ChatRequestMessage {
+    pub(crate) role: &'static str,
+    pub(crate) content: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) images: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) tool_calls: Vec<ToolCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) tool_name: Option<String>,
+}
+
+impl ChatRequestMessage {
+    pub(crate) fn text(role: &'static str, content: String) -> Self {
+        ChatRequestMessage {
+            role,
+            content,
+            images: vec![],
+            tool_calls: vec![],
+            tool_name: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub(crate) struct ToolCall {
+    pub(crate) function: ToolCallFunction,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub(crate) struct ToolCallFunction {
+    pub(crate) name: String,
+    pub(crate) arguments: serde_json::Value,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub(crate) struct ChatResponse {
+    pub(crate) message: ChatResponseMessage,
+    pub(crate) done: bool,
+    // only complete on the final chunk
+    #[serde(flatten)]
+    pub(crate) metadata: GenerationMetadata,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, sqlx::FromRow)]
+#[sqlx(default)]
+pub(crate) struct GenerationMetadata {
+    pub(crate) model: Option<String>,
+    pub(crate) created_at: Option<String>,
+    pub(crate) total_duration: Option<i64>,
+    pub(crate) load_duration: Option<i64>,
+    pub(crate) prompt_eval_count: Option<i64>,
+    pub(crate) prompt_eval_duration: Option<i64>,
+    pub(crate) eval_count: Option<i64>,
+    pub(crate) eval_duration: Option<i64>,
+    pub(crate) done_reason: Option<String>,
+}
+
+impl GenerationMetadata {
+    pub(crate) fn tokens_per_second(&self) -> Option<f64> {
+        match (self.eval_count, self.eval_duration) {
+            (Some(eval_count), Some(eval_duration)) if eval_duration > 0 => {
+                Some(eval_count as f64 / (eval_duration as f64 / 1_000_000_000.0))
+            }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn time_to_first_token_ms(&self) -> Option<f64> {
+        if self.load_duration.is_none() && self.prompt_eval_duration.is_none() {
+            return None;
+        }
+
+        let nanos = self.load_duration.unwrap_or(0) + self.prompt_eval_duration.unwrap_or(0);
+
+        Some(nanos as f64 / 1_000_000.0)
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub(crate) struct ChatResponseMessage {
+    // role: String,
+    pub(crate) content: String,
+    #[serde(default)]
+    pub(crate) thinking: Option<String>,
+    #[serde(default)]
+    pub(crate) tool_calls: Vec<ToolCall>,
+}
+
+#[derive(Default)]
+pub(crate) struct ThinkTags {
+    pub(crate) thinking: bool,
+    pub(crate) pending: String,
+}
+
+impl ThinkTags {
+    const OPEN: &str = "<think>";
+    const CLOSE: &str = "</think>";
+
+    pub(crate) fn push(&mut self, text: &str) -> (String, String) {
+        let mut thinking = String::new();
+        let mut content = String::new();
+
+        let mut text = std::mem::take(&mut self.pending) + text;
+
+        loop {
+            let tag = if self.thinking {
+                Self::CLOSE
+            } else {
+                Self::OPEN
+            };
+
+            let output = if self.thinking {
+                &mut thinking
+            } else {
+                &mut content
+            };
+
+            if let Some(i) = text.find(tag) {
+                output.push_str(&text[..i]);
+                text = text[i + tag.len()..].to_string();
+                self.thinking = !self.thinking;
+            } else {
+                // hold back anything that could be the start of a tag split across chunks
+                let held_back = (1..tag.len())
+                    .rev()
+                    .find(|len| text.ends_with(&tag[..*len]))
+                    .unwrap_or(0);
+
+                output.push_str(&text[..text.len() - held_back]);
+                self.pending = text[text.len() - held_back..].to_string();
+
+                return (thinking, content);
+            }
+        }
+    }
+
+    pub(crate) fn finish(&mut self) -> (String, String) {
+        let pending = std::mem::take(&mut self.pending);
+
+        if self.thinking {
+            (pending, String::new())
+        } else {
+            (String::new(), pending)
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct OllamaErrorResponse {
+    pub(crate) error: String,
+}
+
+pub(crate) async fn ndjson_lines(
+    resp: reqwest::Response,
+) -> anyhow::Result<impl Stream<Item = std::io::Result<String>>> {
+    let status = resp.status();
+
+    if !status.is_success() {
+        let error = match resp.json::<OllamaErrorResponse>().await {
+            Ok(error_response) => error_response.error,
+            Err(_) => status.to_string(),
+        };
+
+        anyhow::bail!("Ollama returned an error: {error}");
+    }
+
+    let bytes_stream = resp
+        .bytes_stream()
+        .map_err(std::io::Error::other)
+        .into_async_read();
+
+    let reader = futures::io::BufReader::new(bytes_stream);
+
+    Ok(reader.lines())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn replies(lines: &[&str]) -> Vec<anyhow::Result<ChatResponse>> {
+        let lines: Vec<std::io::Result<String>> =
+            lines.iter().map(|line| Ok(line.to_string())).collect();
+
+        openai_chunks(futures::stream::iter(lines)).collect().await
+    }
+
+    #[tokio::test]
+    async fn tool_calls_are_put_together_from_their_pieces() {
+        let replies = replies(&[
+            r#"data: {"model":"m","choices":[{"delta":{"role":"assistant","tool_calls":[{"index":0,"id":"a","type":"function","function":{"name":"calculator","arguments":""}}]}}]}"#,
+            "",
+            r#"data: {"choices":[{"delta":{"tool_calls":[{"index":1,"id":"b","type":"function","function":{"name":"get_time"}}]}}]}"#,
+            "",
+            r#"data: {"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"expression\": "}}]}}]}"#,
+            "",
+            r#"data: {"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"arguments":"\"6*7\"}"}}]}}]}"#,
+            "",
+            r#"data: {"choices":[{"delta":{},"finish_reason":"tool_calls"}]}"#,
+            "",
+            r#"data: {"choices":[],"usage":{"prompt_tokens":10,"completion_tokens":5}}"#,
+            "",
+            "data: [DONE]",
+        ])
+        .await;
+
+        let [Ok(reply)] = replies.as_slice() else {
+            panic!("expected one reply, got {replies:?}");
+        };
+
+        assert!(reply.done);
+        assert_eq!(reply.message.content, "");
+
+        let tool_calls: Vec<(&str, &serde_json::Value)> = reply
+            .message
+            .tool_calls
+            .iter()
+            .map(|tool_call| {
+                (
+                    tool_call.function.name.as_str(),
+                    &tool_call.function.arguments,
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            tool_calls,
+            [
+                ("calculator", &json!({ "expression": "6*7" })),
+                ("get_time", &json!({}))
+            ]
+        );
+
+        assert_eq!(reply.metadata.model.as_deref(), Some("m"));
+        assert_eq!(reply.metadata.done_reason.as_deref(), Some("tool_calls"));
+        assert_eq!(reply.metadata.prompt_eval_count, Some(10));
+        assert_eq!(reply.metadata.eval_count, Some(5));
+    }
+
+    #[tokio::test]
+    async fn content_and_reasoning_are_passed_on_as_they_arrive() {
+        let replies = replies(&[
+            r#"data: {"choices":[{"delta":{"reasoning_content":"hmm"}}]}"#,
+            r#"data: {"choices":[{"delta":{"content":"hello"}}]}"#,
+            r#"data: {"choices":[{"delta":{"content":""},"finish_reason":"stop"}]}"#,
+            "data: [DONE]",
+        ])
+        .await;
+
+        let replies: Vec<(String, Option<String>, bool)> = replies
+            .into_iter()
+            .map(|reply| {
+                let reply = reply.unwrap();
+                (reply.message.content, reply.message.thinking, reply.done)
+            })
+            .collect();
+
+        assert_eq!(
+            replies,
+            [
+                (String::new(), Some("hmm".to_string()), false),
+                ("hello".to_string(), Some(String::new()), false),
+                (String::new(), None, true),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn tool_arguments_that_are_not_json_are_an_error() {
+        let replies = replies(&[
+            r#"data: {"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"name":"calculator","arguments":"{\"expression\""}}]}}]}"#,
+            "data: [DONE]",
+        ])
+        .await;
+
+        let [Err(e)] = replies.as_slice() else {
+            panic!("expected one error, got {replies:?}");
+        };
+
+        assert!(e.to_string().contains("calculator"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn errors_in_the_stream_are_passed_on() {
+        let replies = replies(&[r#"data: {"error":{"message":"out of memory"}}"#]).await;
+
+        let [Err(e)] = replies.as_slice() else {
+            panic!("expected one error, got {replies:?}");
+        };
+
+        assert_eq!(
+            e.to_string(),
+            "the backend returned an error: out of memory"
+        );
+    }
+
+    #[test]
+    fn options_are_renamed_and_num_ctx_is_left_out() {
+        let request = ChatRequest {
+            model: "m".to_string(),
+            messages: vec![],
+            options: GenerationOptions {
+                temperature: Some(0.5),
+                num_ctx: Some(8192),
+                num_predict: Some(100),
+                stop: Some("\n\nEND".to_string()),
+                ..Default::default()
+            },
+            keep_alive: None,
+            tools: vec![],
+            format: None,
+        };
+
+        let body = openai_request(&request);
+
+        assert_eq!(body["temperature"], json!(0.5));
+        assert_eq!(body["max_tokens"], json!(100));
+        assert_eq!(body["stop"], json!(["END"]));
+        assert!(body.get("num_ctx").is_none());
+        assert!(body.get("options").is_none());
+    }
+}
@@ -4,24 +4,30 @@ const emptyBlock = '\u{3000}';
 var shouldAddCursor = false;
 var timer;
 
-function swap(id, a, b) {
-    let el = document.getElementById(id);
+function swap(el, a, b) {
     el.innerHTML = el.innerHTML.replace(a, b);
 }
 
-function removeAllBlocks(id) {
-    swap(id, emptyBlock, '');
-    swap(id, filledBlock, '');
+function removeAllBlocks(el) {
+    swap(el, emptyBlock, '');
+    swap(el, filledBlock, '');
+}
+
+// the reply an SSE event is for, if it's still being written
+function responseFor(el) {
+    const row = el.closest('tr');
+    return row && row.querySelector('pre.llm-response');
 }
 
 function alternateCursor() {
-    if (shouldAddCursor) {
-        swap('llm-response', emptyBlock, filledBlock);
-        shouldAddCursor = false;
-    } else {
-        swap('llm-response', filledBlock, emptyBlock);
-        shouldAddCursor = true;
+    for (const el of document.querySelectorAll('pre.llm-response')) {
+        if (shouldAddCursor) {
+            swap(el, emptyBlock, filledBlock);
+        } else {
+            swap(el, filledBlock, emptyBlock);
+        }
     }
+    shouldAddCursor = !shouldAddCursor;
 }
 
 function startTimer() {
@@ -34,21 +40,21 @@ function stopTimer() {
 }
 
 document.addEventListener('htmx:sseBeforeMessage', function (e) {
+    const responseEl = responseFor(e.target);
+    if (!responseEl) {
+        return;
+    }
     if (timer) {
         stopTimer();
     }
-    removeAllBlocks('llm-response');
-});
-
-document.addEventListener('htmx:sseClose', function (e) {
-    stopTimer();
-    removeAllBlocks('llm-response');
-    const responseEl = document.getElementById('llm-response');
-    responseEl.removeAttribute('id');
+    removeAllBlocks(responseEl);
+    if (e.detail.type === 'ChatDone' || e.detail.type === 'ChatError') {
+        responseEl.classList.remove('llm-response');
+    }
 });
 
 document.addEventListener('htmx:afterRequest', function (e) {
     if (e.target.id === 'chat-input-form') {
         startTimer();
     }
-});
\ No newline at end of file
+});
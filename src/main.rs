@@ -26,29 +26,41 @@
 // - [x] store model on conversation,
 //       to persist it when switching between conversations
 
-use axum::extract::{Path, State};
-use axum::http::{HeaderMap, HeaderValue};
-use axum::response::Sse;
+use axum::extract::{DefaultBodyLimit, Multipart, Path, Query, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
 use axum::response::sse::Event;
+use axum::response::{IntoResponse, Sse};
 use axum::routing::{delete, get, post, put};
 use axum::{Form, Router};
+use backend::{
+    BackendKind, ChatBackend, ChatRequest, ChatRequestMessage, GenerationMetadata,
+    GenerationOptions, OllamaErrorResponse, ThinkTags, ToolCall, ToolCallFunction, ndjson_lines,
+};
+use base64::prelude::*;
 use clap::Parser;
-use futures::{AsyncBufReadExt, Stream, TryStreamExt};
+use futures::Stream;
 use maud::{DOCTYPE, Markup, html};
 use serde::{Deserialize, Serialize};
-use sqlx::pool::PoolConnection;
 use sqlx::{Acquire, Sqlite};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::convert::Infallible;
 use std::fmt::Display;
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
-use tokio::sync::{Mutex, broadcast};
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore, broadcast, watch};
+use tokio::task::JoinHandle;
 use tokio_stream::StreamExt;
-use tracing::{debug, error};
+use tracing::{debug, error, info, warn};
+
+mod backend;
 
 const FILLED_BLOCK: char = '\u{2588}';
 
+const DEFAULT_CONVERSATION_NAME: &str = "a new conversation";
+
+const BACKEND_SYNC_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
 macro_rules! layout {
     ($content:expr) => {
         html! {
@@ -78,6 +90,9 @@ macro_rules! layout {
                     pre {
                         white-space: pre-wrap;
                     }
+                    details.thinking:has(> pre:empty) {
+                        display: none;
+                    }
                     "
                 }
                 script {
@@ -91,1148 +106,7830 @@ macro_rules! layout {
     }
 }
 
-#[derive(Deserialize, Clone, Debug)]
-struct ChatResponse {
-    // model: String,
-    // created_at: String,
-    response: String,
-    done: bool,
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+enum ResponseFormat {
+    #[default]
+    Text,
+    Json,
+    Schema,
 }
 
-#[derive(Clone)]
-enum OllamaResponseMessage {
-    More { response: String },
-    Done,
+impl ResponseFormat {
+    fn to_format(self, json_schema: Option<&str>) -> anyhow::Result<Option<serde_json::Value>> {
+        match self {
+            ResponseFormat::Text => Ok(None),
+            ResponseFormat::Json => Ok(Some(serde_json::Value::from("json"))),
+            ResponseFormat::Schema => {
+                let schema =
+                    serde_json::from_str(json_schema.unwrap_or_default()).map_err(|e| {
+                        anyhow::anyhow!("the conversation's JSON schema is not valid JSON: {e}")
+                    })?;
+
+                Ok(Some(schema))
+            }
+        }
+    }
 }
 
-async fn send_chat_message(
-    client: reqwest::Client,
-    messages: &[Message],
-    model: String,
-    ollama_tx: broadcast::Sender<OllamaResponseMessage>,
-) -> anyhow::Result<()> {
-    let mut prompt = String::new();
+fn check_structured_reply(reply: &str, format: &serde_json::Value) -> Result<(), String> {
+    let reply: serde_json::Value =
+        serde_json::from_str(reply).map_err(|e| format!("the reply is not valid JSON: {e}"))?;
 
-    for message in messages {
-        prompt.push_str(&message.who.to_string());
-        prompt.push_str(": ");
-        prompt.push_str(&message.body);
-        prompt.push('\n');
+    // JSON mode has no schema to check against
+    if format.is_string() {
+        return Ok(());
     }
 
-    let body = HashMap::from([("model", model), ("prompt", prompt)]);
+    let validator = jsonschema::validator_for(format)
+        .map_err(|e| format!("the JSON schema is not valid: {e}"))?;
 
-    tokio::spawn(async move {
-        let resp = client
-            .post("http://localhost:11434/api/generate")
-            .json(&body)
-            .send()
-            .await
-            .unwrap();
+    let errors: Vec<String> = validator
+        .iter_errors(&reply)
+        .map(|error| {
+            let path = error.instance_path().to_string();
 
-        let bytes_stream = resp
-            .bytes_stream()
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
-            .into_async_read();
-
-        let reader = futures::io::BufReader::new(bytes_stream);
-
-        let mut lines = reader.lines();
-
-        while let Some(line) = lines.next().await {
-            match line {
-                Ok(line) => {
-                    if let Ok(chat_response) = serde_json::from_str::<ChatResponse>(&line) {
-                        if chat_response.done {
-                            let _ = ollama_tx.send(OllamaResponseMessage::Done);
-                            debug!("sent DONE to ollama_tx");
-                        } else {
-                            let _ = ollama_tx.send(OllamaResponseMessage::More {
-                                response: chat_response.response,
-                            });
-                            debug!("sent More to ollama_tx");
-                        }
-                    } else {
-                        // TODO we should be able to propagate some error response
-                        // to the client here.
-                        error!("could not deserialize chat chunk: {:?}", line);
-                    }
-                }
-                Err(e) => {
-                    // TODO we should be able to propagate some error response
-                    // to the client here.
-                    error!("error receiving ndjson stream: {:?}", e);
-                }
+            if path.is_empty() {
+                error.to_string()
+            } else {
+                format!("at {path}: {error}")
             }
+        })
+        .collect();
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("\n"))
+    }
+}
+
+fn is_valid_keep_alive(keep_alive: &str) -> bool {
+    if keep_alive.parse::<i64>().is_ok() {
+        return true;
+    }
+
+    let mut rest = keep_alive.strip_prefix(['-', '+']).unwrap_or(keep_alive);
+
+    if rest.is_empty() {
+        return false;
+    }
+
+    while !rest.is_empty() {
+        let amount_len = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let (amount, after_amount) = rest.split_at(amount_len);
+
+        if amount.parse::<f64>().is_err() {
+            return false;
         }
-    });
 
-    Ok(())
+        // two-letter units first, so `ms` isn't read as `m`
+        let Some(unit) = ["ns", "us", "µs", "μs", "ms", "s", "m", "h"]
+            .into_iter()
+            .find(|unit| after_amount.starts_with(unit))
+        else {
+            return false;
+        };
+
+        rest = &after_amount[unit.len()..];
+    }
+
+    true
 }
 
-#[derive(Deserialize, Debug)]
-struct OllamaModelsResponse {
-    models: Vec<OllamaModelResponse>,
+async fn get_generation_options(
+    conn: &mut sqlx::SqliteConnection,
+    conversation_id: i64,
+) -> sqlx::Result<GenerationOptions> {
+    sqlx::query_as(
+        "
+    select
+        temperature,
+        top_p,
+        top_k,
+        num_ctx,
+        seed,
+        repeat_penalty,
+        stop,
+        num_predict
+    from conversations
+    where id = ?
+    limit 1;
+    ",
+    )
+    .bind(conversation_id)
+    .fetch_one(conn)
+    .await
 }
 
-#[derive(Deserialize, Debug)]
-struct OllamaModelResponse {
-    name: String,
+impl ChatRequestMessage {
+    fn new(message: &Message, images: Vec<String>) -> Self {
+        match message.who {
+            Who::ToolCall => ChatRequestMessage {
+                role: message.who.role(),
+                content: String::new(),
+                images,
+                tool_calls: vec![ToolCall {
+                    function: ToolCallFunction {
+                        name: message.tool_name.clone().unwrap_or_default(),
+                        arguments: serde_json::from_str(&message.body).unwrap_or_default(),
+                    },
+                }],
+                tool_name: None,
+            },
+            _ => ChatRequestMessage {
+                role: message.who.role(),
+                content: message.body.clone(),
+                images,
+                tool_calls: vec![],
+                tool_name: message.tool_name.clone(),
+            },
+        }
+    }
 }
 
-async fn get_available_models(client: reqwest::Client) -> anyhow::Result<Vec<String>> {
-    let models: OllamaModelsResponse = client
-        .get("http://localhost:11434/api/tags")
-        .send()
-        .await?
-        .json()
-        .await?;
+#[derive(Clone, Debug)]
+enum OllamaResponseMessage {
+    More {
+        response: String,
+    },
+    Thinking {
+        thinking: String,
+    },
+    Done {
+        metadata: GenerationMetadata,
+        format_check: Option<Result<(), String>>,
+    },
+    Error {
+        message_id: i64,
+        error: String,
+    },
+    ToolCalls {
+        messages: Vec<(usize, Message)>,
+    },
+    Stopped,
+}
 
-    let models = models.models.into_iter().map(|m| m.name).collect();
+#[derive(Debug)]
+struct Generation {
+    task: JoinHandle<()>,
+    tx: GenerationTx,
+}
 
-    Ok(models)
+/// how much of a reply someone has seen.
+/// it's the id of each SSE event, as `body.thinking.tool_message`,
+/// so browsers that reconnect say where they got to
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(default)]
+struct ReplyOffset {
+    /// in bytes
+    body: usize,
+    /// in bytes
+    thinking: usize,
+    /// the id of the last tool call or result shown
+    tool_message: i64,
 }
 
-#[derive(Clone, Debug, sqlx::FromRow)]
-struct Model {
-    id: i64,
-    name: String,
+impl ReplyOffset {
+    fn advance(&mut self, chat_chunk: OllamaResponseMessage) -> Option<OllamaResponseMessage> {
+        match chat_chunk {
+            OllamaResponseMessage::More { ref response } => self.body += response.len(),
+            OllamaResponseMessage::Thinking { ref thinking } => self.thinking += thinking.len(),
+            // tool messages are stored before they're sent,
+            // so a page can already show some that are still on their way
+            OllamaResponseMessage::ToolCalls { mut messages } => {
+                messages.retain(|(_, message)| message.id > self.tool_message);
+
+                let last = messages.iter().map(|(_, message)| message.id).max()?;
+
+                self.tool_message = last;
+
+                return Some(OllamaResponseMessage::ToolCalls { messages });
+            }
+            _ => (),
+        }
+
+        Some(chat_chunk)
+    }
 }
 
-#[derive(Clone, Debug, sqlx::FromRow)]
-struct Message {
-    id: i64,
+impl Display for ReplyOffset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.body, self.thinking, self.tool_message)
+    }
+}
+
+impl FromStr for ReplyOffset {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('.');
+
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(body), Some(thinking), Some(tool_message), None) => Ok(ReplyOffset {
+                body: body.parse()?,
+                thinking: thinking.parse()?,
+                tool_message: tool_message.parse()?,
+            }),
+            _ => anyhow::bail!("expected BODY.THINKING.TOOL_MESSAGE, got `{s}`"),
+        }
+    }
+}
+
+/// everything a reply has said so far
+#[derive(Debug, Default)]
+struct Written {
     body: String,
-    who: String,
-    conversation_id: i64,
-    inserted_at: String,
+    thinking: String,
+    tool_messages: Vec<(usize, Message)>,
+    /// how it ended, once it has
+    end: Option<OllamaResponseMessage>,
 }
 
-#[derive(sqlx::FromRow)]
-struct Conversation {
-    id: i64,
-    name: String,
-    model: String,
-    source_conversation_id: Option<i64>,
-    source_conversation_name: Option<String>,
-    inserted_at: String,
-    // updated_at: String,
+impl Written {
+    fn has_reached(&self, offset: ReplyOffset) -> bool {
+        self.body.is_char_boundary(offset.body) && self.thinking.is_char_boundary(offset.thinking)
+    }
+
+    fn since(&self, offset: ReplyOffset) -> Vec<OllamaResponseMessage> {
+        let mut chat_chunks = vec![];
+
+        if !self.tool_messages.is_empty() {
+            chat_chunks.push(OllamaResponseMessage::ToolCalls {
+                messages: self.tool_messages.clone(),
+            });
+        }
+
+        if let Some(thinking) = self.thinking.get(offset.thinking..)
+            && !thinking.is_empty()
+        {
+            chat_chunks.push(OllamaResponseMessage::Thinking {
+                thinking: thinking.to_string(),
+            });
+        }
+
+        if let Some(response) = self.body.get(offset.body..)
+            && !response.is_empty()
+        {
+            chat_chunks.push(OllamaResponseMessage::More {
+                response: response.to_string(),
+            });
+        }
+
+        chat_chunks.extend(self.end.clone());
+
+        chat_chunks
+    }
 }
 
-#[derive(sqlx::FromRow)]
-struct ConversationWithLastMessageTime {
-    id: i64,
-    name: String,
-    source_conversation_id: Option<i64>,
-    source_conversation_name: Option<String>,
-    inserted_at: String,
-    // updated_at: String,
-    last_message_inserted_at: String,
+const GENERATION_CHANNEL_CAPACITY: usize = 256;
+
+#[derive(Clone, Debug)]
+struct GenerationTx {
+    tx: broadcast::Sender<OllamaResponseMessage>,
+    written: Arc<std::sync::Mutex<Written>>,
 }
 
-async fn conversations_index(
-    State(state): State<Arc<Mutex<AppState>>>,
-) -> axum::response::Result<maud::Markup> {
-    let state = state.lock().await;
+impl GenerationTx {
+    fn new() -> (Self, broadcast::Receiver<OllamaResponseMessage>) {
+        let (tx, rx) = broadcast::channel(GENERATION_CHANNEL_CAPACITY);
 
-    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+        (
+            GenerationTx {
+                tx,
+                written: Arc::default(),
+            },
+            rx,
+        )
+    }
 
-    let conversations: Vec<ConversationWithLastMessageTime> = sqlx::query_as(
-        "
-        select
-            conversations.id,
-            conversations.name,
-            c2.name as source_conversation_name,
-            c2.id as source_conversation_id,
-            conversations.inserted_at,
-            -- conversations.updated_at,
-            last_messages.last_message_inserted_at
-        from conversations
-        inner join (
-            select 
-                conversation_id,
-                max(inserted_at) as last_message_inserted_at
-            from messages
-            group by conversation_id
-        ) last_messages
-            on last_messages.conversation_id = conversations.id
-        left join conversations c2
-            on conversations.source_conversation_id = c2.id
-        order by conversations.inserted_at desc;
-        ",
-    )
-    .fetch_all(&mut *conn)
-    .await
-    .map_err(|e| e.to_string())?;
+    fn send(&self, chat_chunk: OllamaResponseMessage) {
+        // held while sending, so nobody can subscribe in between and see it twice, or never
+        let mut written = self
+            .written
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
 
-    Ok(layout! {
-        html! {
-            div class="container mb-5" {
-                nav class="level" {
-                    div class="level-left" {
-                        div class="level-item" {
-                            h2 class="subtitle" {
-                                "conversations"
-                            }
-                        }
+        match &chat_chunk {
+            OllamaResponseMessage::More { response } => written.body.push_str(response),
+            OllamaResponseMessage::Thinking { thinking } => written.thinking.push_str(thinking),
+            OllamaResponseMessage::ToolCalls { messages } => {
+                written.tool_messages.extend(messages.iter().cloned())
+            }
+            OllamaResponseMessage::Done { .. }
+            | OllamaResponseMessage::Error { .. }
+            | OllamaResponseMessage::Stopped => written.end = Some(chat_chunk.clone()),
+        }
 
-                        div class="level-item" {
-                            a hx-post="/conversations/new" {
-                                "new conversation"
-                            }
-                        }
-                    }
-                }
-                table class="table container" {
-                    thead {
-                        tr {
-                            th { "started" }
-                            th { "last message" }
-                            th { "name" }
-                            th { "source" }
-                        }
-                    }
+        let _ = self.tx.send(chat_chunk);
+    }
 
-                    @for conversation in conversations {
-                        tbody {
-                            tr {
-                                td {
-                                    (conversation.inserted_at)
-                                }
-                                td {
-                                    (conversation.last_message_inserted_at)
-                                }
-                                td {
-                                    a href=(format!("/conversations/{}", conversation.id)) {
-                                        (conversation.name)
-                                    }
-                                }
-                                @if let Some(source_conversation_name) = conversation.source_conversation_name {
-                                    td {
-                                        a href=(format!("/conversations/{}", conversation.source_conversation_id.unwrap())) {
-                                            (source_conversation_name)
-                                        }
-                                    }
-                                }
+    fn subscribe(
+        &self,
+        offset: ReplyOffset,
+    ) -> (
+        Vec<OllamaResponseMessage>,
+        broadcast::Receiver<OllamaResponseMessage>,
+    ) {
+        let written = self
+            .written
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        (written.since(offset), self.tx.subscribe())
+    }
+
+    fn watch(self, offset: ReplyOffset) -> impl Stream<Item = OllamaResponseMessage> {
+        let (missed, ollama_rx) = self.subscribe(offset);
+
+        futures::stream::unfold(
+            (self, ollama_rx, VecDeque::from(missed), offset),
+            |(ollama_tx, mut ollama_rx, mut missed, mut offset)| async move {
+                loop {
+                    let chat_chunk = match missed.pop_front() {
+                        Some(chat_chunk) => chat_chunk,
+                        None => match ollama_rx.recv().await {
+                            Ok(chat_chunk) => chat_chunk,
+                            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                                debug!("a watcher fell {skipped} chunks behind, catching it up");
+
+                                let (caught_up, resubscribed) = ollama_tx.subscribe(offset);
+
+                                missed.extend(caught_up);
+                                ollama_rx = resubscribed;
+
+                                continue;
                             }
-                        }
+                            Err(broadcast::error::RecvError::Closed) => return None,
+                        },
+                    };
+
+                    if let Some(chat_chunk) = offset.advance(chat_chunk) {
+                        return Some((chat_chunk, (ollama_tx, ollama_rx, missed, offset)));
                     }
                 }
-            }
+            },
+        )
+    }
+
+    fn has_reached(&self, offset: ReplyOffset) -> bool {
+        self.written
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .has_reached(offset)
+    }
+
+    fn text(&self) -> (String, String) {
+        let written = self
+            .written
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        (written.body.clone(), written.thinking.clone())
+    }
+
+    fn same_channel(&self, other: &GenerationTx) -> bool {
+        Arc::ptr_eq(&self.written, &other.written)
+    }
+}
+
+type Generations = Arc<Mutex<HashMap<i64, Generation>>>;
+
+impl ChatRequest {
+    fn new(
+        model: String,
+        system_prompt: &str,
+        messages: &[Message],
+        images: &HashMap<i64, Vec<String>>,
+        options: GenerationOptions,
+        keep_alive: Option<String>,
+    ) -> Self {
+        let system_message = if system_prompt.trim().is_empty() {
+            None
+        } else {
+            Some(ChatRequestMessage::text(
+                "system",
+                system_prompt.to_string(),
+            ))
+        };
+
+        ChatRequest {
+            model,
+            messages: system_message
+                .into_iter()
+                .chain(messages.iter().map(|message| {
+                    ChatRequestMessage::new(
+                        message,
+                        images.get(&message.id).cloned().unwrap_or_default(),
+                    )
+                }))
+                .collect(),
+            options,
+            keep_alive,
+            tools: vec![],
+            format: None,
         }
-    })
+    }
 }
 
-async fn conversations_show(
-    State(state): State<Arc<Mutex<AppState>>>,
-    Path(conversation_id): Path<i64>,
-) -> axum::response::Result<maud::Markup> {
-    let state = state.lock().await;
+const MAX_TOOL_ROUNDS: usize = 10;
 
-    let available_models = get_available_models(state.http_client.clone())
-        .await
-        .map_err(|e| e.to_string())?;
+async fn stream_chat_response_with_tools(
+    chat_backend: &dyn ChatBackend,
+    mut body: ChatRequest,
+    ollama_response_message_id: i64,
+    ollama_tx: &GenerationTx,
+    tools: &Tools,
+) -> anyhow::Result<()> {
+    for _ in 0..MAX_TOOL_ROUNDS {
+        let (written_before, _) = ollama_tx.text();
 
-    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+        let tool_calls = stream_chat_response(chat_backend, &body, ollama_tx).await?;
 
-    drop(state);
+        if tool_calls.is_empty() {
+            return Ok(());
+        }
 
-    let mut txn = conn.begin().await.map_err(|e| e.to_string())?;
+        let messages = tools.run(ollama_response_message_id, &tool_calls).await?;
 
-    // TODO determine if we want to do this every time we load a conversation
-    for model in available_models {
-        sqlx::query(
-            "
-        insert into models
-        (name) values (?)
-        on conflict do nothing;
-        ",
-        )
-        .bind(model)
-        .execute(&mut *txn)
-        .await
-        .map_err(|e| e.to_string())?;
+        let mut round_messages: Vec<ChatRequestMessage> = messages
+            .iter()
+            .map(|(_, message)| ChatRequestMessage::new(message, vec![]))
+            .collect();
+
+        // anything the model wrote before calling the tools belongs to the same turn
+        if let Some(tool_call) = round_messages.first_mut() {
+            let (written, _) = ollama_tx.text();
+            tool_call.content = written[written_before.len()..].to_string();
+        }
+
+        body.messages.extend(round_messages);
+
+        ollama_tx.send(OllamaResponseMessage::ToolCalls { messages });
     }
 
-    let models: Vec<Model> = sqlx::query_as(
-        "
-        select
-            id,
-            name
-        from models
-        order by name;
-        ",
-    )
-    .fetch_all(&mut *txn)
-    .await
-    .map_err(|e| e.to_string())?;
+    anyhow::bail!("the model was still calling tools after {MAX_TOOL_ROUNDS} rounds")
+}
 
-    let conversation: Conversation = sqlx::query_as(
-        "
-    select
-        conversations.id,
-        conversations.name,
-        models.name as model,
-        conversations.source_conversation_id,
-        c2.name as source_conversation_name,
-        conversations.inserted_at
-        -- conversations.updated_at
-    from conversations
-    left join conversations c2
-        on conversations.source_conversation_id = c2.id
-    inner join models
-        on models.id = conversations.model_id
-    where conversations.id = ?
-    limit 1;
-    ",
-    )
-    .bind(conversation_id)
-    .fetch_one(&mut *txn)
-    .await
-    .map_err(|e| e.to_string())?;
+/// sends a reply's text to `ollama_tx`, with any think tags split out.
+/// what it holds back as a possible partial tag is sent when it's dropped,
+/// so it isn't lost when the reply fails or is stopped partway
+struct ReplyText<'a> {
+    ollama_tx: &'a GenerationTx,
+    think_tags: Option<ThinkTags>,
+}
 
-    let messages: Vec<Message> = sqlx::query_as(
-        "
-        select
-            id,
-            body,
-            who,
-            conversation_id,
-            inserted_at
-         from messages where conversation_id = ?;",
-    )
-    .bind(conversation_id)
-    .fetch_all(&mut *txn)
-    .await
-    .map_err(|e| e.to_string())?;
+impl ReplyText<'_> {
+    fn send(&self, thinking: String, response: String) {
+        if !thinking.is_empty() {
+            self.ollama_tx
+                .send(OllamaResponseMessage::Thinking { thinking });
+        }
 
-    txn.commit().await.map_err(|e| e.to_string())?;
+        if !response.is_empty() {
+            self.ollama_tx
+                .send(OllamaResponseMessage::More { response });
+            debug!("sent More to ollama_tx");
+        }
+    }
 
-    Ok(layout! {
-        html! {
-            div class="container mb-5" {
-                section class="section" {
-                    a href="/conversations/" {
-                        "Back"
-                    }
-                    div class="level" {
-                        div
-                            id="conversation-name-block"
-                            class="level-left"
-                        {
-                            div class="level-item" {
-                                h1 class="title" {
-                                    (conversation.name)
-                                }
-                            }
-                            div
-                                id="conversation-name-edit"
-                                class="level-item"
-                            {
-                                a
-                                    hx-get=(format!("/conversations/{}/edit", conversation.id))
-                                    hx-swap="outerHTML"
-                                    hx-target="#conversation-name-edit"
-                                {
-                                    "Edit"
-                                }
-                            }
-                        }
-                    }
+    fn push(&mut self, content: String) {
+        let (thinking, response) = match &mut self.think_tags {
+            Some(think_tags) => think_tags.push(&content),
+            None => (String::new(), content),
+        };
 
-                    h2 class="subtitle" {
-                        "Started: " (conversation.inserted_at)
-                    }
+        self.send(thinking, response);
+    }
 
-                    @if let Some(last_message) = messages.last() {
-                        h2 class="subtitle" {
-                            "Last message at: " (last_message.inserted_at)
-                        }
-                    }
+    fn finish(&mut self) {
+        if let Some(think_tags) = &mut self.think_tags {
+            let (thinking, response) = think_tags.finish();
+            self.send(thinking, response);
+        }
+    }
+}
 
-                    @if let Some(source_conversation_name) = conversation.source_conversation_name {
-                        h2 class="subtitle" {
-                            "Source: "
-                            a href=(format!("/conversations/{}", conversation.source_conversation_id.unwrap())) {
-                                (source_conversation_name)
-                            }
-                        }
-                    }
+impl Drop for ReplyText<'_> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
 
-                    a
-                        hx-delete=(format!("/conversations/{}/delete", conversation.id))
-                        hx-confirm="Really delete? Conversation and all messages will be destroyed."
-                    {
-                        "Delete conversation"
-                    }
+async fn stream_chat_response(
+    chat_backend: &dyn ChatBackend,
+    body: &ChatRequest,
+    ollama_tx: &GenerationTx,
+) -> anyhow::Result<Vec<ToolCall>> {
+    let mut chunks = chat_backend.chat(body).await?;
 
-                    div {
-                        select
-                            name="model-id"
-                            hx-put=(format!("/models/select/{}", conversation.id))
-                            hx-swap="none"
-                        {
-                            @for model in models.iter() {
-                                @if *model.name == conversation.model {
-                                    option value=(model.id) selected {
-                                        (model.name)
-                                    }
-                                } @else {
-                                    option value=(model.id) {
-                                        (model.name)
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
+    let mut tool_calls = vec![];
 
-                table class="table container" {
-                    thead {
-                        tr {
-                            th {
-                                ""
-                            }
-                            th {
-                                ""
-                            }
-                            th {
-                                ""
-                            }
-                            th {
-                                ""
-                            }
-                            th {
-                                ""
-                            }
-                        }
-                    }
-                    tbody id="messages" {
-                        @for (i, message) in messages.iter().enumerate() {
-                            tr {
-                                td {
-                                    (i + 1)
-                                }
-                                td {
-                                    (message.inserted_at)
-                                }
-                                td {
-                                    (message.who.to_string())
-                                }
-                                td {
-                                    pre {
-                                        (message.body)
-                                    }
-                                }
-                                td {
-                                    a hx-post=(format!("/conversations/{}/fork/{}", conversation.id, message.id)) {
-                                        "Fork"
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-                div {
-                    form
-                        id="chat-input-form"
-                        hx-post="/messages/new"
-                        hx-target="#messages"
-                        hx-swap="beforeend"
-                        // https://htmx.org/examples/keyboard-shortcuts/
-                        // hx-trigger="keyup[metaKey&&key=='Enter'], keyup[shiftKey&&key=='Enter'] from:body"
-                        hx-on::after-request="if(event.detail.successful) this.reset()"
-                    {
-                        div class="field" {
-                            div class="control" {
-                                textarea
-                                    class="textarea"
-                                    name="body" {}
-                            }
-                        }
+    let mut reply_text = ReplyText {
+        ollama_tx,
+        // a reply asked to be JSON is all reply, even if it happens to contain a <think> tag
+        think_tags: body.format.is_none().then(ThinkTags::default),
+    };
 
-                        input type="hidden" name="conversation_id" value=(conversation.id) {}
-                        div class="field-body" {
-                            div class="field is-grouped" {
-                                div class="control" {
-                                    button
-                                        class="button is-link"
-                                    {
-                                        "Send"
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
+    while let Some(chat_response) = chunks.next().await {
+        let mut chat_response = chat_response?;
+
+        tool_calls.append(&mut chat_response.message.tool_calls);
+
+        reply_text.send(
+            chat_response.message.thinking.unwrap_or_default(),
+            String::new(),
+        );
+
+        reply_text.push(chat_response.message.content);
+
+        if chat_response.done {
+            reply_text.finish();
+
+            if !tool_calls.is_empty() {
+                return Ok(tool_calls);
             }
+
+            // exactly what's stored, which includes anything written in earlier tool rounds
+            let (reply, _) = ollama_tx.text();
+
+            let format_check = body
+                .format
+                .as_ref()
+                .map(|format| check_structured_reply(&reply, format));
+
+            ollama_tx.send(OllamaResponseMessage::Done {
+                metadata: chat_response.metadata,
+                format_check,
+            });
+            debug!("sent DONE to ollama_tx");
+
+            return Ok(tool_calls);
         }
-    })
+    }
+
+    anyhow::bail!("the backend stopped responding before the reply was done")
 }
 
-#[derive(Deserialize)]
-struct MessageSendForm {
-    body: String,
-    conversation_id: i64,
+#[derive(Clone, Debug, sqlx::FromRow)]
+struct Model {
+    id: i64,
+    name: String,
+    available: bool,
+    size: Option<i64>,
+    backend_id: i64,
+    backend_name: String,
+    backend_url: String,
+    backend_kind: BackendKind,
 }
 
-async fn messages_create(
-    State(state): State<Arc<Mutex<AppState>>>,
-    Form(message_send_form): Form<MessageSendForm>,
-) -> axum::response::Result<Markup> {
-    let conversation_id = message_send_form.conversation_id;
+impl Model {
+    fn label(&self, with_backend: bool) -> String {
+        let mut label = self.name.clone();
 
-    let state = state.lock().await;
+        if with_backend {
+            label.push_str(&format!(" ({})", self.backend_name));
+        }
 
-    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
-    let conn2 = state.pool.acquire().await.map_err(|e| e.to_string())?;
-    let http_client = state.http_client.clone();
-    let ollama_tx = state.ollama_tx.clone();
-    let ollama_rx2 = state.ollama_rx.resubscribe();
+        if !self.available {
+            label.push_str(" (removed)");
+        }
 
-    drop(state);
+        label
+    }
+}
 
-    let mut txn = conn.begin().await.map_err(|e| e.to_string())?;
+#[derive(Clone, Debug, sqlx::FromRow)]
+struct Backend {
+    id: i64,
+    name: String,
+    url: String,
+    kind: BackendKind,
+}
 
-    let message: Message = sqlx::query_as(
-        "
-    insert into messages (
-        who,
-        body,
-        conversation_id
-    ) values (?, ?, ?)
-    returning 
-        id,
-        body,
-        who,
-        conversation_id,
-        inserted_at;",
-    )
-    .bind(Who::Me)
-    .bind(message_send_form.body)
-    .bind(conversation_id)
-    .fetch_one(&mut *txn)
-    .await
-    .map_err(|e| e.to_string())?;
+type ChatBackends = Arc<HashMap<i64, Arc<dyn ChatBackend>>>;
 
-    let messages: Vec<Message> = sqlx::query_as(
-        "
-        select 
-            id,
-            body,
-            who,
-            conversation_id,
-            inserted_at
-        from messages
+type UnreachableBackends = Arc<Mutex<BTreeMap<String, String>>>;
+
+fn spawn_backend_sync_task(
+    pool: sqlx::Pool<Sqlite>,
+    chat_backends: ChatBackends,
+    backends: Vec<Backend>,
+    unreachable_backends: UnreachableBackends,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(BACKEND_SYNC_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            for backend in backends.iter() {
+                let Some(chat_backend) = chat_backends.get(&backend.id) else {
+                    continue;
+                };
+
+                match sync_backend_models(&pool, chat_backend.as_ref(), backend.id).await {
+                    Ok(()) => {
+                        if unreachable_backends
+                            .lock()
+                            .await
+                            .remove(&backend.name)
+                            .is_some()
+                        {
+                            info!("backend {} is reachable again", backend.name);
+                        }
+                    }
+                    Err(e) => {
+                        warn!(
+                            "could not sync models from backend {}: {:?}",
+                            backend.name, e
+                        );
+
+                        unreachable_backends
+                            .lock()
+                            .await
+                            .insert(backend.name.clone(), e.to_string());
+                    }
+                }
+            }
+        }
+    });
+}
+
+async fn sync_backend_models(
+    pool: &sqlx::Pool<Sqlite>,
+    chat_backend: &dyn ChatBackend,
+    backend_id: i64,
+) -> anyhow::Result<()> {
+    let available_models = chat_backend.models().await?;
+
+    let mut conn = pool.acquire().await?;
+
+    let mut txn = conn.begin().await?;
+
+    // models can't be deleted outright while conversations still use them,
+    // so anything the backend doesn't list anymore is marked unavailable
+    sqlx::query(
+        "
+    update models
+    set available = 0
+    where backend_id = ?;
+    ",
+    )
+    .bind(backend_id)
+    .execute(&mut *txn)
+    .await?;
+
+    for model in available_models {
+        sqlx::query(
+            "
+        insert into models
+        (name, backend_id, size) values (?, ?, ?)
+        on conflict (backend_id, name) do update set
+            available = 1,
+            size = excluded.size,
+            updated_at = STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW');
+        ",
+        )
+        .bind(model.name)
+        .bind(backend_id)
+        .bind(model.size)
+        .execute(&mut *txn)
+        .await?;
+    }
+
+    txn.commit().await?;
+
+    Ok(())
+}
+
+fn backend_banner(unreachable_backends: &BTreeMap<String, String>) -> Markup {
+    html! {
+        div
+            id="backend-banner"
+            hx-get="/backends/status"
+            hx-trigger="every 10s"
+            hx-swap="outerHTML"
+        {
+            @for (name, error) in unreachable_backends {
+                div class="notification is-warning" {
+                    "Can't reach the " strong { (name) } " backend: " (error) ". "
+                    "Showing the models it had last time, and trying to reconnect."
+                }
+            }
+        }
+    }
+}
+
+async fn backends_status(State(state): State<Arc<AppState>>) -> axum::response::Result<Markup> {
+    let unreachable_backends = state.unreachable_backends.lock().await.clone();
+
+    Ok(backend_banner(&unreachable_backends))
+}
+
+#[derive(Clone, Debug, sqlx::FromRow)]
+struct Message {
+    id: i64,
+    body: String,
+    #[sqlx(default)]
+    thinking: String,
+    who: Who,
+    conversation_id: i64,
+    inserted_at: String,
+    status: MessageStatus,
+    error: Option<String>,
+    version_of: Option<i64>,
+    // only selected where versions are shown
+    #[sqlx(default)]
+    version_number: i64,
+    #[sqlx(default)]
+    version_count: i64,
+    #[sqlx(default)]
+    attachment_ids: Option<String>,
+    #[sqlx(default)]
+    tool_name: Option<String>,
+    #[sqlx(default)]
+    reply_id: Option<i64>,
+    #[sqlx(default)]
+    reply_first_version_id: Option<i64>,
+    #[sqlx(default)]
+    json_valid: Option<bool>,
+    #[sqlx(default)]
+    json_error: Option<String>,
+    #[sqlx(default)]
+    pinned: bool,
+    #[sqlx(default)]
+    context_cut_before: Option<i64>,
+    #[sqlx(default)]
+    context_summary: Option<String>,
+    #[sqlx(flatten)]
+    metadata: GenerationMetadata,
+}
+
+impl Message {
+    fn first_version_id(&self) -> i64 {
+        self.version_of.unwrap_or(self.id)
+    }
+
+    fn position_id(&self) -> i64 {
+        self.reply_first_version_id
+            .unwrap_or_else(|| self.first_version_id())
+    }
+
+    fn attachment_ids(&self) -> impl Iterator<Item = &str> {
+        self.attachment_ids
+            .iter()
+            .flat_map(|attachment_ids| attachment_ids.split(','))
+    }
+}
+
+async fn get_message(conn: &mut sqlx::SqliteConnection, message_id: i64) -> sqlx::Result<Message> {
+    sqlx::query_as(
+        "
+        select
+            id,
+            body,
+            thinking,
+            who,
+            conversation_id,
+            inserted_at,
+            status,
+            error,
+            version_of,
+            tool_name,
+            reply_id,
+            reply_first_version_id,
+            json_valid,
+            json_error,
+            pinned,
+            context_cut_before,
+            context_summary,
+            (
+                select count(*)
+                from messages versions
+                where coalesce(versions.version_of, versions.id) = coalesce(messages.version_of, messages.id)
+                and versions.id <= messages.id
+            ) as version_number,
+            (
+                select count(*)
+                from messages versions
+                where coalesce(versions.version_of, versions.id) = coalesce(messages.version_of, messages.id)
+            ) as version_count,
+            (
+                select group_concat(attachments.id)
+                from attachments
+                where attachments.message_id = messages.id
+            ) as attachment_ids,
+            model,
+            created_at,
+            total_duration,
+            load_duration,
+            prompt_eval_count,
+            prompt_eval_duration,
+            eval_count,
+            eval_duration,
+            done_reason
+        from messages
+        where id = ?
+        limit 1;
+        ",
+    )
+    .bind(message_id)
+    .fetch_one(conn)
+    .await
+}
+
+async fn message_position(
+    conn: &mut sqlx::SqliteConnection,
+    message: &Message,
+) -> sqlx::Result<usize> {
+    let (position,): (i64,) = sqlx::query_as(
+        "
+        select count(*)
+        from messages
         where conversation_id = ?
-        order by inserted_at, id;
+        and canonical = 1
+        and (inserted_at, coalesce(reply_first_version_id, version_of, id), reply_id is null, id)
+            <= (?, ?, ?, ?);
         ",
     )
-    .bind(conversation_id)
-    .fetch_all(&mut *txn)
+    .bind(message.conversation_id)
+    .bind(&message.inserted_at)
+    .bind(message.position_id())
+    .bind(message.reply_id.is_none())
+    .bind(message.id)
+    .fetch_one(conn)
+    .await?;
+
+    Ok(position as usize)
+}
+
+#[derive(sqlx::FromRow)]
+struct Conversation {
+    id: i64,
+    name: String,
+    model_id: i64,
+    system_prompt: String,
+    keep_alive: Option<String>,
+    tools_enabled: bool,
+    response_format: ResponseFormat,
+    json_schema: Option<String>,
+    context_strategy: ContextStrategy,
+    source_conversation_id: Option<i64>,
+    source_conversation_name: Option<String>,
+    inserted_at: String,
+    // updated_at: String,
+}
+
+#[derive(sqlx::FromRow)]
+struct ConversationWithLastMessageTime {
+    id: i64,
+    name: String,
+    source_conversation_id: Option<i64>,
+    source_conversation_name: Option<String>,
+    inserted_at: String,
+    // updated_at: String,
+    last_message_inserted_at: String,
+}
+
+async fn conversations_index(
+    State(state): State<Arc<AppState>>,
+) -> axum::response::Result<maud::Markup> {
+    let unreachable_backends = state.unreachable_backends.lock().await.clone();
+
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    let conversations: Vec<ConversationWithLastMessageTime> = sqlx::query_as(
+        "
+        select
+            conversations.id,
+            conversations.name,
+            c2.name as source_conversation_name,
+            c2.id as source_conversation_id,
+            conversations.inserted_at,
+            -- conversations.updated_at,
+            last_messages.last_message_inserted_at
+        from conversations
+        inner join (
+            select 
+                conversation_id,
+                max(inserted_at) as last_message_inserted_at
+            from messages
+            group by conversation_id
+        ) last_messages
+            on last_messages.conversation_id = conversations.id
+        left join conversations c2
+            on conversations.source_conversation_id = c2.id
+        -- answers still being compared are shown on the comparison
+        where conversations.id not in (select conversation_id from comparison_forks)
+        order by conversations.inserted_at desc;
+        ",
+    )
+    .fetch_all(&mut *conn)
     .await
     .map_err(|e| e.to_string())?;
 
-    // create the reply from llama.
-    // initially, it's empty.
-    let ollama_response: Message = sqlx::query_as(
+    let interrupted = interrupted_replies(&mut conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(layout! {
+        html! {
+            div class="container mb-5" {
+                (backend_banner(&unreachable_backends))
+                @if !interrupted.is_empty() {
+                    div class="notification is-warning is-light" {
+                        @if interrupted.len() == 1 {
+                            "A reply was cut off when ochat stopped. "
+                        } @else {
+                            (interrupted.len()) " replies were cut off when ochat stopped. "
+                        }
+                        button class="button is-small" hx-post="/generations/resume" {
+                            "Resume"
+                        }
+                    }
+                }
+                nav class="level" {
+                    div class="level-left" {
+                        div class="level-item" {
+                            h2 class="subtitle" {
+                                "conversations"
+                            }
+                        }
+
+                        div class="level-item" {
+                            a hx-post="/conversations/new" {
+                                "new conversation"
+                            }
+                        }
+
+                        div class="level-item" {
+                            a href="/models" {
+                                "models"
+                            }
+                        }
+                    }
+                }
+                table class="table container" {
+                    thead {
+                        tr {
+                            th { "started" }
+                            th { "last message" }
+                            th { "name" }
+                            th { "source" }
+                        }
+                    }
+
+                    @for conversation in conversations {
+                        tbody {
+                            tr {
+                                td {
+                                    (conversation.inserted_at)
+                                }
+                                td {
+                                    (conversation.last_message_inserted_at)
+                                }
+                                td {
+                                    a href=(format!("/conversations/{}", conversation.id)) {
+                                        (conversation.name)
+                                    }
+                                }
+                                @if let Some(source_conversation_name) = conversation.source_conversation_name {
+                                    td {
+                                        a href=(format!("/conversations/{}", conversation.source_conversation_id.unwrap())) {
+                                            (source_conversation_name)
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+async fn conversations_show(
+    State(state): State<Arc<AppState>>,
+    Path(conversation_id): Path<i64>,
+) -> axum::response::Result<maud::Markup> {
+    let unreachable_backends = state.unreachable_backends.lock().await.clone();
+
+    let has_files_dir = state.tools.files_dir.is_some();
+
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    let backends = get_configured_backends(&mut conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut txn = conn.begin().await.map_err(|e| e.to_string())?;
+
+    let conversation: Conversation = sqlx::query_as(
         "
-        insert into messages (
-            who,
-            body,
-            conversation_id
-        ) values (?, ?, ?)
-         returning *;
-         ",
+    select
+        conversations.id,
+        conversations.name,
+        conversations.model_id,
+        conversations.system_prompt,
+        conversations.keep_alive,
+        conversations.tools_enabled,
+        conversations.response_format,
+        conversations.json_schema,
+        conversations.context_strategy,
+        conversations.source_conversation_id,
+        c2.name as source_conversation_name,
+        conversations.inserted_at
+        -- conversations.updated_at
+    from conversations
+    left join conversations c2
+        on conversations.source_conversation_id = c2.id
+    where conversations.id = ?
+    limit 1;
+    ",
     )
-    .bind(Who::Llama)
-    .bind("")
     .bind(conversation_id)
     .fetch_one(&mut *txn)
     .await
     .map_err(|e| e.to_string())?;
 
-    let model: Model = sqlx::query_as(
-        "
-    select
-        models.id,
-        models.name
-    from models
-    inner join conversations
-        on conversations.model_id = models.id
-    where conversations.id = ?
-    limit 1;
-    ",
-    )
-    .bind(conversation_id)
-    .fetch_one(&mut *txn)
-    .await
-    .map_err(|e| e.to_string())?;
+    let models = get_available_models(&mut txn, Some(conversation.model_id))
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let options = get_generation_options(&mut txn, conversation_id)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let message_ids: Vec<i64> = sqlx::query_scalar(
+        "
+        select id
+        from messages
+        where conversation_id = ?
+        and canonical = 1
+        order by inserted_at, coalesce(reply_first_version_id, version_of, id), reply_id is null, id;
+        ",
+    )
+    .bind(conversation_id)
+    .fetch_all(&mut *txn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let mut messages = vec![];
+
+    for message_id in message_ids {
+        messages.push(
+            get_message(&mut txn, message_id)
+                .await
+                .map_err(|e| e.to_string())?,
+        );
+    }
+
+    let comparisons: Vec<(i64, String)> = sqlx::query_as(
+        "
+        select
+            id,
+            prompt
+        from comparisons
+        where conversation_id = ?
+        order by id;
+        ",
+    )
+    .bind(conversation_id)
+    .fetch_all(&mut *txn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    txn.commit().await.map_err(|e| e.to_string())?;
+
+    // where the context of the latest reply started
+    let context_cut = messages
+        .iter()
+        .rev()
+        .find(|message| message.who == Who::Llama)
+        .and_then(|reply| {
+            reply
+                .context_cut_before
+                .map(|cut_before| (cut_before, reply.context_summary.as_deref()))
+        });
+
+    // so a reply still being written doesn't send the tool calls shown here again
+    let last_tool_message = messages
+        .iter()
+        .filter(|message| matches!(message.who, Who::ToolCall | Who::ToolResult))
+        .map(|message| message.id)
+        .max()
+        .unwrap_or_default();
+
+    Ok(layout! {
+        html! {
+            div class="container mb-5" {
+                section class="section" {
+                    (backend_banner(&unreachable_backends))
+                    a href="/conversations/" {
+                        "Back"
+                    }
+                    div class="level" {
+                        div
+                            id="conversation-name-block"
+                            class="level-left"
+                        {
+                            div class="level-item" {
+                                h1 class="title" {
+                                    (conversation.name)
+                                }
+                            }
+                            div
+                                id="conversation-name-edit"
+                                class="level-item"
+                            {
+                                a
+                                    hx-get=(format!("/conversations/{}/edit", conversation.id))
+                                    hx-swap="outerHTML"
+                                    hx-target="#conversation-name-edit"
+                                {
+                                    "Edit"
+                                }
+                            }
+                        }
+                    }
+
+                    h2 class="subtitle" {
+                        "Started: " (conversation.inserted_at)
+                    }
+
+                    @if let Some(last_message) = messages.last() {
+                        h2 class="subtitle" {
+                            "Last message at: " (last_message.inserted_at)
+                        }
+                    }
+
+                    @if let Some(source_conversation_name) = conversation.source_conversation_name {
+                        h2 class="subtitle" {
+                            "Source: "
+                            a href=(format!("/conversations/{}", conversation.source_conversation_id.unwrap())) {
+                                (source_conversation_name)
+                            }
+                        }
+                    }
+
+                    @for (comparison_id, prompt) in comparisons.iter() {
+                        div class="notification is-info is-light" {
+                            "Comparing models on \u{201c}" (prompt) "\u{201d}. "
+                            a href=(format!("/comparisons/{comparison_id}")) {
+                                "See their answers"
+                            }
+                        }
+                    }
+
+                    a
+                        hx-delete=(format!("/conversations/{}/delete", conversation.id))
+                        hx-confirm="Really delete? Conversation and all messages will be destroyed."
+                    {
+                        "Delete conversation"
+                    }
+
+                    div class="mt-3" {
+                        select
+                            name="model-id"
+                            hx-put=(format!("/models/select/{}", conversation.id))
+                            hx-swap="none"
+                        {
+                            @for model in models.iter() {
+                                @let label = model.label(backends.len() > 1);
+                                @if model.id == conversation.model_id {
+                                    option value=(model.id) selected {
+                                        (label)
+                                    }
+                                } @else {
+                                    option value=(model.id) {
+                                        (label)
+                                    }
+                                }
+                            }
+                        }
+                        a class="ml-2" href="/models" {
+                            "Manage models"
+                        }
+                    }
+
+                    div class="mt-3" {
+                        label class="label is-small" {
+                            "When the conversation no longer fits in the model's context"
+                        }
+                        div class="select is-small" {
+                            select
+                                name="context_strategy"
+                                hx-put=(format!("/conversations/{}/context-strategy", conversation.id))
+                                hx-swap="none"
+                            {
+                                @for (context_strategy, value, label) in [
+                                    (ContextStrategy::All, "all", "Send everything anyway"),
+                                    (ContextStrategy::DropOldest, "drop_oldest", "Leave out the oldest messages"),
+                                    (ContextStrategy::Summarize, "summarize", "Summarize the oldest messages"),
+                                ] {
+                                    option
+                                        value=(value)
+                                        selected[context_strategy == conversation.context_strategy]
+                                    {
+                                        (label)
+                                    }
+                                }
+                            }
+                        }
+                        p class="help" {
+                            "Pinned messages are always sent."
+                        }
+                    }
+
+                    form
+                        class="mt-3"
+                        hx-put=(format!("/conversations/{}/keep-alive", conversation.id))
+                        hx-target="next .help"
+                        hx-swap="innerHTML"
+                    {
+                        div class="field has-addons" {
+                            div class="control" {
+                                a class="button is-small is-static" {
+                                    "Keep model loaded for"
+                                }
+                            }
+                            div class="control" {
+                                input
+                                    class="input is-small"
+                                    type="text"
+                                    name="keep_alive"
+                                    placeholder="5m"
+                                    value=[conversation.keep_alive];
+                            }
+                            div class="control" {
+                                button class="button is-small" {
+                                    "Save"
+                                }
+                            }
+                        }
+                    }
+                    p class="help" {
+                        "Like 30s, 10m or 1h30m. 0 unloads the model after every reply, -1 keeps it loaded."
+                    }
+
+                    label class="checkbox mt-3" {
+                        input
+                            type="checkbox"
+                            name="tools_enabled"
+                            checked[conversation.tools_enabled]
+                            hx-put=(format!("/conversations/{}/tools", conversation.id))
+                            hx-swap="none";
+                        " Let the model use tools: a calculator, the date and time, your conversation history"
+                        @if has_files_dir {
+                            " and the shared files"
+                        }
+                    }
+
+                    details class="mt-3" {
+                        summary {
+                            "Generation options"
+                        }
+                        form
+                            hx-put=(format!("/conversations/{}/options", conversation.id))
+                            hx-swap="none"
+                        {
+                            div class="columns is-multiline mt-1" {
+                                (option_input("temperature", "Temperature", "0.01", options.temperature))
+                                (option_input("top_p", "Top P", "0.01", options.top_p))
+                                (option_input("top_k", "Top K", "1", options.top_k))
+                                (option_input("num_ctx", "Context length (num_ctx)", "1", options.num_ctx))
+                                (option_input("seed", "Seed", "1", options.seed))
+                                (option_input("repeat_penalty", "Repeat penalty", "0.01", options.repeat_penalty))
+                                (option_input("num_predict", "Max tokens (num_predict)", "1", options.num_predict))
+                                div class="column is-one-quarter" {
+                                    div class="field" {
+                                        label class="label is-small" {
+                                            "Stop sequences (one per line)"
+                                        }
+                                        div class="control" {
+                                            textarea
+                                                class="textarea is-small"
+                                                name="stop"
+                                                rows="2"
+                                            {
+                                                (options.stop.clone().unwrap_or_default())
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                            div class="control" {
+                                button class="button is-small" {
+                                    "Save options"
+                                }
+                            }
+                        }
+                    }
+
+                    details class="mt-3" open[conversation.response_format != ResponseFormat::Text] {
+                        summary {
+                            "Structured output"
+                        }
+                        form
+                            class="mt-1"
+                            hx-put=(format!("/conversations/{}/response-format", conversation.id))
+                            hx-target="find .help"
+                            hx-swap="innerHTML"
+                        {
+                            div class="field" {
+                                div class="control" {
+                                    div class="select is-small" {
+                                        select name="response_format" {
+                                            @for (response_format, value, label) in [
+                                                (ResponseFormat::Text, "text", "Text"),
+                                                (ResponseFormat::Json, "json", "JSON"),
+                                                (ResponseFormat::Schema, "schema", "JSON matching this schema"),
+                                            ] {
+                                                option
+                                                    value=(value)
+                                                    selected[response_format == conversation.response_format]
+                                                {
+                                                    (label)
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                            div class="field" {
+                                div class="control" {
+                                    textarea
+                                        class="textarea is-small is-family-monospace"
+                                        name="json_schema"
+                                        rows="4"
+                                        placeholder=r#"{"type": "object", "properties": {"name": {"type": "string"}}, "required": ["name"]}"#
+                                    {
+                                        (conversation.json_schema.clone().unwrap_or_default())
+                                    }
+                                }
+                                p class="help" {}
+                            }
+                            div class="control" {
+                                button class="button is-small" {
+                                    "Save output format"
+                                }
+                            }
+                        }
+                    }
+
+                    form
+                        class="mt-3"
+                        hx-put=(format!("/conversations/{}/system-prompt", conversation.id))
+                        hx-swap="none"
+                    {
+                        div class="field" {
+                            label class="label" {
+                                "System prompt"
+                            }
+                            div class="control" {
+                                textarea
+                                    class="textarea"
+                                    name="system_prompt"
+                                    rows="3"
+                                {
+                                    (conversation.system_prompt)
+                                }
+                            }
+                        }
+                        div class="control" {
+                            button class="button is-small" {
+                                "Save system prompt"
+                            }
+                        }
+                    }
+                }
+
+                table class="table container" {
+                    thead {
+                        tr {
+                            th {
+                                ""
+                            }
+                            th {
+                                ""
+                            }
+                            th {
+                                ""
+                            }
+                            th {
+                                ""
+                            }
+                            th {
+                                ""
+                            }
+                        }
+                    }
+                    tbody id="messages" {
+                        @for (i, message) in messages.iter().enumerate() {
+                            @if let Some((_, summary)) = context_cut.filter(|(cut_before, _)| *cut_before == message.id) {
+                                (context_cut_marker(summary))
+                            }
+                            @if message.status == MessageStatus::Generating {
+                                (streaming_message_row(i + 1, message, last_tool_message))
+                            } @else {
+                                (message_row(i + 1, message))
+                            }
+                        }
+                    }
+                }
+                div {
+                    form
+                        id="chat-input-form"
+                        hx-post="/messages/new"
+                        hx-target="#messages"
+                        hx-swap="beforeend"
+                        hx-encoding="multipart/form-data"
+                        // pasted images are added to the file input
+                        hx-on:paste="
+                            if (event.clipboardData.files.length === 0) return;
+                            const input = this.querySelector('input[name=images]');
+                            const files = new DataTransfer();
+                            for (const file of input.files) files.items.add(file);
+                            for (const file of event.clipboardData.files) files.items.add(file);
+                            input.files = files.files;
+                        "
+                        // https://htmx.org/examples/keyboard-shortcuts/
+                        // hx-trigger="keyup[metaKey&&key=='Enter'], keyup[shiftKey&&key=='Enter'] from:body"
+                        hx-on::after-request="if(event.detail.successful) this.reset()"
+                    {
+                        div class="field" {
+                            div class="control" {
+                                textarea
+                                    class="textarea"
+                                    name="body" {}
+                            }
+                        }
+
+                        input type="hidden" name="conversation_id" value=(conversation.id) {}
+                        div class="field-body" {
+                            div class="field is-grouped" {
+                                div class="control" {
+                                    button
+                                        class="button is-link"
+                                    {
+                                        "Send"
+                                    }
+                                }
+                                div class="control" {
+                                    input
+                                        class="input"
+                                        type="file"
+                                        name="images"
+                                        accept="image/png,image/jpeg,image/gif,image/webp"
+                                        multiple;
+                                }
+                            }
+                        }
+                    }
+
+                    details class="mt-3" {
+                        summary {
+                            "Compare models"
+                        }
+                        form
+                            class="mt-1"
+                            hx-post=(format!("/conversations/{}/compare", conversation.id))
+                        {
+                            div class="field" {
+                                div class="control" {
+                                    textarea
+                                        class="textarea"
+                                        name="body"
+                                        placeholder="asked of every model checked below, each in its own fork of this conversation"
+                                        required {}
+                                }
+                            }
+                            div class="field" {
+                                @for model in models.iter().filter(|model| model.available) {
+                                    label class="checkbox mr-4" {
+                                        input type="checkbox" name="model_id" value=(model.id);
+                                        " " (model.label(backends.len() > 1))
+                                    }
+                                }
+                            }
+                            div class="control" {
+                                button class="button is-link is-small" {
+                                    "Compare"
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+fn generation_stats(metadata: &GenerationMetadata) -> Markup {
+    html! {
+        @if let Some(eval_count) = metadata.eval_count {
+            p class="help" {
+                @if let Some(model) = &metadata.model {
+                    (model) " · "
+                }
+                (eval_count) " tokens"
+                @if let Some(tokens_per_second) = metadata.tokens_per_second() {
+                    " · " (format!("{tokens_per_second:.1}")) " tokens/s"
+                }
+                @if let Some(time_to_first_token_ms) = metadata.time_to_first_token_ms() {
+                    " · " (format!("{time_to_first_token_ms:.0}")) " ms to first token"
+                }
+            }
+        }
+    }
+}
+
+fn option_input<T: Display>(name: &str, label: &str, step: &str, value: Option<T>) -> Markup {
+    html! {
+        div class="column is-one-quarter" {
+            div class="field" {
+                label class="label is-small" {
+                    (label)
+                }
+                div class="control" {
+                    input
+                        class="input is-small"
+                        type="number"
+                        step=(step)
+                        name=(name)
+                        value=[value];
+                }
+            }
+        }
+    }
+}
+
+const MAX_MESSAGE_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Default)]
+struct MessageSendForm {
+    body: String,
+    conversation_id: i64,
+    images: Vec<Image>,
+}
+
+struct Image {
+    content_type: String,
+    data: Vec<u8>,
+}
+
+impl MessageSendForm {
+    async fn from_multipart(mut multipart: Multipart) -> anyhow::Result<Self> {
+        let mut form = MessageSendForm::default();
+
+        while let Some(field) = multipart.next_field().await? {
+            match field.name() {
+                Some("body") => form.body = field.text().await?,
+                Some("conversation_id") => form.conversation_id = field.text().await?.parse()?,
+                Some("images") => {
+                    let file_name = field.file_name().unwrap_or_default().to_string();
+                    let data = field.bytes().await?;
+
+                    // the browser sends an empty file when none are picked
+                    if data.is_empty() {
+                        continue;
+                    }
+
+                    // what the browser says the file is can't be trusted, so look at the bytes
+                    let Some(content_type) = image_content_type(&data) else {
+                        anyhow::bail!(
+                            "only PNG, JPEG, GIF and WebP images can be attached, and {file_name} isn't one"
+                        );
+                    };
+
+                    form.images.push(Image {
+                        content_type: content_type.to_string(),
+                        data: data.to_vec(),
+                    });
+                }
+                _ => (),
+            }
+        }
+
+        Ok(form)
+    }
+}
+
+fn image_content_type(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if data.starts_with(b"\xff\xd8\xff") {
+        Some("image/jpeg")
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+async fn messages_create(
+    State(state): State<Arc<AppState>>,
+    multipart: Multipart,
+) -> axum::response::Result<Markup> {
+    let message_send_form = MessageSendForm::from_multipart(multipart)
+        .await
+        .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()))?;
+
+    let conversation_id = message_send_form.conversation_id;
+
+    let pool = state.pool.clone();
+    let queue = state.queue.clone();
+
+    let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+
+    let mut txn = conn.begin().await.map_err(|e| e.to_string())?;
+
+    let mut message: Message = sqlx::query_as(
+        "
+    insert into messages (
+        who,
+        body,
+        conversation_id
+    ) values (?, ?, ?)
+    returning 
+        id,
+        body,
+        who,
+        conversation_id,
+        inserted_at,
+        status,
+        error,
+        version_of;",
+    )
+    .bind(Who::Me)
+    .bind(message_send_form.body)
+    .bind(conversation_id)
+    .fetch_one(&mut *txn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let mut attachment_ids = vec![];
+
+    for image in message_send_form.images {
+        let (attachment_id,): (i64,) = sqlx::query_as(
+            "
+        insert into attachments (
+            message_id,
+            content_type,
+            data
+        ) values (?, ?, ?)
+        returning id;
+        ",
+        )
+        .bind(message.id)
+        .bind(image.content_type)
+        .bind(image.data)
+        .fetch_one(&mut *txn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        attachment_ids.push(attachment_id.to_string());
+    }
+
+    if !attachment_ids.is_empty() {
+        message.attachment_ids = Some(attachment_ids.join(","));
+    }
+
+    // create the reply from llama.
+    // initially, it's empty.
+    let ollama_response: Message = sqlx::query_as(
+        "
+        insert into messages (
+            who,
+            body,
+            conversation_id,
+            status
+        ) values (?, ?, ?, ?)
+         returning *;
+         ",
+    )
+    .bind(Who::Llama)
+    .bind("")
+    .bind(conversation_id)
+    .bind(MessageStatus::Generating)
+    .fetch_one(&mut *txn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let count = message_position(&mut txn, &message)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    txn.commit().await.map_err(|e| e.to_string())?;
+
+    queue
+        .push(&ollama_response)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(html! {
+        (message_row(count, &message))
+        (streaming_message_row(count + 1, &ollama_response, 0))
+    })
+}
+
+async fn messages_retry(
+    State(state): State<Arc<AppState>>,
+    Path(message_id): Path<i64>,
+) -> axum::response::Result<Markup> {
+    let pool = state.pool.clone();
+    let queue = state.queue.clone();
+
+    let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+
+    let ollama_response = reset_reply(&mut conn, message_id)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let count = message_position(&mut conn, &ollama_response)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    queue
+        .push(&ollama_response)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(html! {
+        (hide_tool_messages(ollama_response.first_version_id()))
+        (streaming_message_row(count, &ollama_response, 0))
+    })
+}
+
+async fn reset_reply(conn: &mut sqlx::SqliteConnection, message_id: i64) -> sqlx::Result<Message> {
+    sqlx::query(
+        "
+        delete from messages
+        where reply_id = ?
+        ",
+    )
+    .bind(message_id)
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query_as(
+        "
+        update messages
+        set
+            body = '',
+            thinking = '',
+            status = ?,
+            error = null,
+            json_valid = null,
+            json_error = null,
+            model = null,
+            created_at = null,
+            total_duration = null,
+            load_duration = null,
+            prompt_eval_count = null,
+            prompt_eval_duration = null,
+            eval_count = null,
+            eval_duration = null,
+            done_reason = null,
+            updated_at = STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')
+        where id = ?
+        and who = ?
+        returning *;
+        ",
+    )
+    .bind(MessageStatus::Generating)
+    .bind(message_id)
+    .bind(Who::Llama)
+    .fetch_one(conn)
+    .await
+}
+
+async fn messages_queue(
+    State(state): State<Arc<AppState>>,
+    Path(message_id): Path<i64>,
+) -> axum::response::Result<axum::response::Response> {
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    let ahead = GenerationQueue::position(&mut conn, message_id)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let Some(ahead) = ahead else {
+        // htmx stops polling on a 286
+        return Ok(StatusCode::from_u16(286)
+            .map_err(|e| e.to_string())?
+            .into_response());
+    };
+
+    Ok(html! {
+        p class="help" {
+            @match ahead {
+                0 => "Waiting for the backend. This reply is next.",
+                1 => "Waiting for the backend. 1 reply is ahead of this one.",
+                _ => { "Waiting for the backend. " (ahead) " replies are ahead of this one." }
+            }
+        }
+    }
+    .into_response())
+}
+
+async fn generations_resume(
+    State(state): State<Arc<AppState>>,
+) -> axum::response::Result<HeaderMap> {
+    let pool = state.pool.clone();
+    let queue = state.queue.clone();
+
+    let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+
+    for message_id in interrupted_replies(&mut conn)
+        .await
+        .map_err(|e| e.to_string())?
+    {
+        let ollama_response = reset_reply(&mut conn, message_id)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        // it's marked failed, and shows why, so the others can carry on
+        if let Err(e) = queue.push(&ollama_response).await {
+            error!("could not resume reply {}: {:?}", message_id, e);
+        }
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Redirect", HeaderValue::from_static("/conversations/"));
+
+    Ok(headers)
+}
+
+async fn interrupted_replies(conn: &mut sqlx::SqliteConnection) -> sqlx::Result<Vec<i64>> {
+    sqlx::query_scalar(
+        "
+        select generations.message_id
+        from generations
+        inner join messages
+            on messages.id = generations.message_id
+        where generations.status = ?
+        and messages.status = ?
+        and not exists (
+            select 1
+            from generations later
+            where later.message_id = generations.message_id
+            and later.id > generations.id
+        )
+        order by generations.id;
+        ",
+    )
+    .bind(GenerationStatus::Interrupted)
+    .bind(MessageStatus::Failed)
+    .fetch_all(conn)
+    .await
+}
+
+async fn messages_regenerate(
+    State(state): State<Arc<AppState>>,
+    Path(message_id): Path<i64>,
+) -> axum::response::Result<Markup> {
+    let pool = state.pool.clone();
+    let queue = state.queue.clone();
+
+    let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+
+    let mut txn = conn.begin().await.map_err(|e| e.to_string())?;
+
+    let message = get_message(&mut txn, message_id)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if message.who != Who::Llama {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "only replies can be regenerated",
+        )
+            .into());
+    }
+
+    // along with the tool calls and results they were based on
+    sqlx::query(
+        "
+        update messages
+        set canonical = 0
+        where coalesce(reply_first_version_id, version_of, id) = ?;
+        ",
+    )
+    .bind(message.first_version_id())
+    .execute(&mut *txn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    // versions share the first version's inserted_at,
+    // so they all sort into the same place in the conversation
+    let ollama_response: Message = sqlx::query_as(
+        "
+        insert into messages (
+            who,
+            body,
+            conversation_id,
+            status,
+            version_of,
+            inserted_at
+        ) values (?, ?, ?, ?, ?, ?)
+        returning *;
+        ",
+    )
+    .bind(Who::Llama)
+    .bind("")
+    .bind(message.conversation_id)
+    .bind(MessageStatus::Generating)
+    .bind(message.first_version_id())
+    .bind(&message.inserted_at)
+    .fetch_one(&mut *txn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let count = message_position(&mut txn, &ollama_response)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    txn.commit().await.map_err(|e| e.to_string())?;
+
+    queue
+        .push(&ollama_response)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(html! {
+        (hide_tool_messages(ollama_response.first_version_id()))
+        (streaming_message_row(count, &ollama_response, 0))
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum VersionDirection {
+    Previous,
+    Next,
+}
+
+async fn messages_version_select(
+    State(state): State<Arc<AppState>>,
+    Path((message_id, direction)): Path<(i64, VersionDirection)>,
+) -> axum::response::Result<Markup> {
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    let mut txn = conn.begin().await.map_err(|e| e.to_string())?;
+
+    let message = get_message(&mut txn, message_id)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let query = match direction {
+        VersionDirection::Previous => {
+            "
+            select id
+            from messages
+            where coalesce(version_of, id) = ?
+            and id < ?
+            order by id desc
+            limit 1;
+            "
+        }
+        VersionDirection::Next => {
+            "
+            select id
+            from messages
+            where coalesce(version_of, id) = ?
+            and id > ?
+            order by id
+            limit 1;
+            "
+        }
+    };
+
+    let selected: Option<(i64,)> = sqlx::query_as(query)
+        .bind(message.first_version_id())
+        .bind(message.id)
+        .fetch_optional(&mut *txn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let selected_id = selected.map(|(id,)| id).unwrap_or(message.id);
+
+    sqlx::query(
+        "
+        update messages
+        set canonical = (id = ? or reply_id is ?)
+        where coalesce(reply_first_version_id, version_of, id) = ?;
+        ",
+    )
+    .bind(selected_id)
+    .bind(selected_id)
+    .bind(message.first_version_id())
+    .execute(&mut *txn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let message = get_message(&mut txn, selected_id)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let tool_message_ids: Vec<(i64,)> = sqlx::query_as(
+        "
+        select id
+        from messages
+        where reply_id = ?
+        order by id;
+        ",
+    )
+    .bind(selected_id)
+    .fetch_all(&mut *txn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let mut tool_messages = vec![];
+
+    for (tool_message_id,) in tool_message_ids {
+        let tool_message = get_message(&mut txn, tool_message_id)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let count = message_position(&mut txn, &tool_message)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        tool_messages.push((count, tool_message));
+    }
+
+    let count = message_position(&mut txn, &message)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    txn.commit().await.map_err(|e| e.to_string())?;
+
+    Ok(html! {
+        (hide_tool_messages(message.first_version_id()))
+        @for (index, tool_message) in &tool_messages {
+            (message_row(*index, tool_message))
+        }
+        (message_row(count, &message))
+    })
+}
+
+fn hide_tool_messages(first_version_id: i64) -> Markup {
+    html! {
+        // rows can only be swapped in from a template
+        template {
+            tr hx-swap-oob=(format!("delete:.tools-of-{first_version_id}")) {}
+        }
+    }
+}
+
+async fn messages_stop(
+    State(state): State<Arc<AppState>>,
+    Path(message_id): Path<i64>,
+) -> axum::response::Result<Markup> {
+    let generations = state.generations.clone();
+
+    // it already finished on its own
+    if !stop_generation(&generations, message_id).await {
+        return Ok(html! {});
+    }
+
+    Ok(stopped_marker())
+}
+
+async fn stop_generation(generations: &Generations, message_id: i64) -> bool {
+    let generation = generations.lock().await.remove(&message_id);
+
+    let Some(generation) = generation else {
+        return false;
+    };
+
+    // dropping the in-flight request is what tells Ollama to stop generating
+    generation.task.abort();
+    let _ = generation.task.await;
+
+    generation.tx.send(OllamaResponseMessage::Stopped);
+
+    true
+}
+
+async fn messages_pin(
+    State(state): State<Arc<AppState>>,
+    Path(message_id): Path<i64>,
+) -> axum::response::Result<Markup> {
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    sqlx::query(
+        "
+        update messages
+        set
+            pinned = not pinned,
+            updated_at = STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')
+        where id = ?
+        ",
+    )
+    .bind(message_id)
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let message = get_message(&mut conn, message_id)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let count = message_position(&mut conn, &message)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(message_row(count, &message))
+}
+
+async fn messages_show(
+    State(state): State<Arc<AppState>>,
+    Path(message_id): Path<i64>,
+) -> axum::response::Result<Markup> {
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    let message = get_message(&mut conn, message_id)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let count = message_position(&mut conn, &message)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(message_row(count, &message))
+}
+
+async fn messages_edit_get(
+    State(state): State<Arc<AppState>>,
+    Path(message_id): Path<i64>,
+) -> axum::response::Result<Markup> {
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    let message = get_message(&mut conn, message_id)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let count = message_position(&mut conn, &message)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(html! {
+        tr {
+            td {
+                (count)
+            }
+            td {
+                (message.inserted_at)
+            }
+            td {
+                (message.who.to_string())
+            }
+            td colspan="2" {
+                form hx-post=(format!("/messages/{}/edit", message.id)) {
+                    div class="field" {
+                        div class="control" {
+                            textarea class="textarea" name="body" required {
+                                (message.body)
+                            }
+                        }
+                    }
+                    p class="help mb-2" {
+                        "Sending starts a new branch from here. This conversation stays as it is."
+                    }
+                    div class="field is-grouped" {
+                        div class="control" {
+                            button class="button is-link is-small" {
+                                "Send"
+                            }
+                        }
+                        div class="control" {
+                            button
+                                type="button"
+                                class="button is-small"
+                                hx-get=(format!("/messages/{}", message.id))
+                                hx-target="closest tr"
+                                hx-swap="outerHTML"
+                            {
+                                "Cancel"
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[derive(Deserialize)]
+struct MessageEditForm {
+    body: String,
+}
+
+async fn messages_edit_save(
+    State(state): State<Arc<AppState>>,
+    Path(message_id): Path<i64>,
+    Form(message_edit_form): Form<MessageEditForm>,
+) -> axum::response::Result<HeaderMap> {
+    let pool = state.pool.clone();
+    let queue = state.queue.clone();
+
+    let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+
+    let mut txn = conn.begin().await.map_err(|e| e.to_string())?;
+
+    let message = get_message(&mut txn, message_id)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if message.who != Who::Me {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "only your own messages can be edited",
+        )
+            .into());
+    }
+
+    let new_conversation_id = fork_conversation(
+        &mut txn,
+        message.conversation_id,
+        ForkPoint::Before(message.id),
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let (edited_message_id,): (i64,) = sqlx::query_as(
+        "
+        insert into messages (
+            who,
+            body,
+            conversation_id
+        ) values (?, ?, ?)
+        returning id;
+        ",
+    )
+    .bind(Who::Me)
+    .bind(message_edit_form.body)
+    .bind(new_conversation_id)
+    .fetch_one(&mut *txn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    // editing only changes the text, so the images come along
+    copy_attachments(&mut txn, message.id, edited_message_id)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let ollama_response: Message = sqlx::query_as(
+        "
+        insert into messages (
+            who,
+            body,
+            conversation_id,
+            status
+        ) values (?, ?, ?, ?)
+         returning *;
+         ",
+    )
+    .bind(Who::Llama)
+    .bind("")
+    .bind(new_conversation_id)
+    .bind(MessageStatus::Generating)
+    .fetch_one(&mut *txn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    txn.commit().await.map_err(|e| e.to_string())?;
+
+    queue
+        .push(&ollama_response)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let path = format!("/conversations/{new_conversation_id}");
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "HX-Redirect",
+        HeaderValue::try_from(path).map_err(|e| e.to_string())?,
+    );
+
+    Ok(headers)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+enum ContextStrategy {
+    #[default]
+    All,
+    DropOldest,
+    Summarize,
+}
+
+const DEFAULT_NUM_CTX: i64 = 4096;
+
+fn estimate_tokens(text: &str) -> i64 {
+    text.len() as i64 / 4 + 4
+}
+
+struct ContextWindow {
+    messages: Vec<Message>,
+    cut_before: Option<i64>,
+    summary: Option<String>,
+}
+
+impl ContextWindow {
+    fn cut(messages: Vec<Message>, cut: usize, summary: Option<String>) -> Self {
+        let cut_before = messages.get(cut).map(|message| message.id);
+
+        ContextWindow {
+            messages: messages
+                .into_iter()
+                .enumerate()
+                .filter(|(i, message)| *i >= cut || message.pinned)
+                .map(|(_, message)| message)
+                .collect(),
+            cut_before,
+            summary,
+        }
+    }
+}
+
+struct Summarizer<'a> {
+    chat_backend: &'a dyn ChatBackend,
+    limits: &'a Limits,
+    model: &'a Model,
+}
+
+async fn fit_context(
+    conn: &mut sqlx::SqliteConnection,
+    summarizer: &Summarizer<'_>,
+    strategy: ContextStrategy,
+    options: &GenerationOptions,
+    system_prompt: &str,
+    messages: Vec<Message>,
+) -> anyhow::Result<ContextWindow> {
+    let tokens = |messages: &[Message], include_pinned: bool| -> i64 {
+        messages
+            .iter()
+            .filter(|message| include_pinned || !message.pinned)
+            .map(|message| estimate_tokens(&message.body))
+            .sum()
+    };
+
+    let num_ctx = options.num_ctx.unwrap_or(DEFAULT_NUM_CTX);
+
+    // a quarter of the context is left for the reply
+    let budget = num_ctx - num_ctx / 4 - estimate_tokens(system_prompt);
+
+    if strategy == ContextStrategy::All || messages.is_empty() || tokens(&messages, true) <= budget
+    {
+        return Ok(ContextWindow {
+            messages,
+            cut_before: None,
+            summary: None,
+        });
+    }
+
+    // pinned messages are always sent, wherever the cut is
+    let budget = budget
+        - messages
+            .iter()
+            .filter(|message| message.pinned)
+            .map(|message| estimate_tokens(&message.body))
+            .sum::<i64>();
+
+    // the most recent summary, and where it was cut
+    let previous_summary: Option<(usize, String)> = if strategy == ContextStrategy::Summarize {
+        let previous_summary: Option<(i64, String)> = sqlx::query_as(
+            "
+        select
+            context_cut_before,
+            context_summary
+        from messages
+        where conversation_id = ?
+        and context_summary is not null
+        order by inserted_at desc, id desc
+        limit 1;
+        ",
+        )
+        .bind(messages[0].conversation_id)
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        previous_summary.and_then(|(cut_before, summary)| {
+            messages
+                .iter()
+                .position(|message| message.id == cut_before)
+                .map(|cut| (cut, summary))
+        })
+    } else {
+        None
+    };
+
+    // summarizing is slow, so keep using the last summary while everything after it still fits
+    if let Some((cut, summary)) = &previous_summary
+        && tokens(&messages[*cut..], false) + estimate_tokens(summary) <= budget
+    {
+        return Ok(ContextWindow::cut(messages, *cut, Some(summary.clone())));
+    }
+
+    // and cut deeper when summarizing, to leave room for the conversation to grow
+    // before the next summary
+    let target = match strategy {
+        ContextStrategy::Summarize => budget / 2,
+        _ => budget,
+    };
+
+    let mut cut = messages.len();
+
+    while cut > 0 && tokens(&messages[cut - 1..], false) <= target {
+        cut -= 1;
+    }
+
+    // the latest message is always sent, and a tool result is never sent without its call
+    let last = messages.len() - 1;
+
+    while cut < last && messages[cut].who == Who::ToolResult {
+        cut += 1;
+    }
+
+    let cut = cut.min(last);
+
+    if strategy == ContextStrategy::DropOldest {
+        return Ok(ContextWindow::cut(messages, cut, None));
+    }
+
+    // only what the last summary doesn't already cover needs summarizing
+    let (previous_summary, from) = match previous_summary {
+        Some((previous_cut, summary)) if previous_cut <= cut => (Some(summary), previous_cut),
+        _ => (None, 0),
+    };
+
+    let summary = summarize_messages(
+        summarizer,
+        previous_summary.as_deref(),
+        messages[from..cut].iter().filter(|message| !message.pinned),
+    )
+    .await?;
+
+    Ok(ContextWindow::cut(messages, cut, Some(summary)))
+}
+
+async fn summarize_messages<'a>(
+    summarizer: &Summarizer<'_>,
+    previous_summary: Option<&str>,
+    messages: impl Iterator<Item = &'a Message>,
+) -> anyhow::Result<String> {
+    let mut transcript = String::new();
+
+    if let Some(previous_summary) = previous_summary {
+        transcript.push_str(&format!(
+            "(a summary of what came before)\n{previous_summary}\n\n"
+        ));
+    }
+
+    for message in messages {
+        transcript.push_str(&format!("{}: {}\n\n", message.who.role(), message.body));
+    }
+
+    let request = ChatRequest {
+        model: summarizer.model.name.clone(),
+        messages: vec![
+            ChatRequestMessage::text(
+                "system",
+                "Summarize this conversation between a user and an assistant in a few short paragraphs. \
+                Keep names, numbers, decisions and anything the user asked to be remembered."
+                    .to_string(),
+            ),
+            ChatRequestMessage::text("user", transcript),
+        ],
+        options: GenerationOptions::default(),
+        keep_alive: None,
+        tools: vec![],
+        format: None,
+    };
+
+    let _permit = summarizer
+        .limits
+        .acquire(summarizer.model.backend_id)
+        .await?;
+
+    summarizer
+        .chat_backend
+        .complete(&request)
+        .await
+        .map_err(|e| anyhow::anyhow!("could not summarize the conversation: {e}"))
+}
+
+async fn chat_request(
+    conn: &mut sqlx::SqliteConnection,
+    chat_backends: &ChatBackends,
+    limits: &Limits,
+    tools: &Tools,
+    ollama_response: &Message,
+) -> anyhow::Result<(ChatRequest, Arc<dyn ChatBackend>)> {
+    let conversation_id = ollama_response.conversation_id;
+
+    let messages: Vec<Message> = sqlx::query_as(
+        "
+        select 
+            id,
+            body,
+            who,
+            conversation_id,
+            inserted_at,
+            status,
+            error,
+            version_of,
+            tool_name,
+            reply_id,
+            reply_first_version_id,
+            pinned
+        from messages
+        where conversation_id = ?
+        and canonical = 1
+        and (inserted_at, coalesce(reply_first_version_id, version_of, id)) < (?, ?)
+        and status != ?
+        order by inserted_at, coalesce(reply_first_version_id, version_of, id), reply_id is null, id;
+        ",
+    )
+    .bind(conversation_id)
+    .bind(&ollama_response.inserted_at)
+    .bind(ollama_response.position_id())
+    .bind(MessageStatus::Failed)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let model = get_conversation_model(conn, conversation_id)
+        .await
+        .map_err(|e| anyhow::anyhow!("could not find this conversation's model: {e}"))?;
+
+    let chat_backend = chat_backends
+        .get(&model.backend_id)
+        .cloned()
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "the {} backend isn't configured anymore",
+                model.backend_name
+            )
+        })?;
+
+    let (
+        system_prompt,
+        keep_alive,
+        tools_enabled,
+        response_format,
+        json_schema,
+        context_strategy,
+    ): (
+        String,
+        Option<String>,
+        bool,
+        ResponseFormat,
+        Option<String>,
+        ContextStrategy,
+    ) = sqlx::query_as(
+        "
+    select
+        system_prompt,
+        keep_alive,
+        tools_enabled,
+        response_format,
+        json_schema,
+        context_strategy
+    from conversations
+    where id = ?
+    limit 1;
+    ",
+    )
+    .bind(conversation_id)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    let options = get_generation_options(conn, conversation_id).await?;
+
+    let format = response_format.to_format(json_schema.as_deref())?;
+
+    let context = fit_context(
+        conn,
+        &Summarizer {
+            chat_backend: chat_backend.as_ref(),
+            limits,
+            model: &model,
+        },
+        context_strategy,
+        &options,
+        &system_prompt,
+        messages,
+    )
+    .await?;
+
+    sqlx::query(
+        "
+    update messages
+    set
+        context_cut_before = ?,
+        context_summary = ?
+    where id = ?
+    ",
+    )
+    .bind(context.cut_before)
+    .bind(&context.summary)
+    .bind(ollama_response.id)
+    .execute(&mut *conn)
+    .await?;
+
+    let messages = context.messages;
+
+    let system_prompt = match &context.summary {
+        Some(summary) => format!(
+            "{system_prompt}\n\nA summary of the earlier part of this conversation:\n{summary}"
+        )
+        .trim_start()
+        .to_string(),
+        None => system_prompt,
+    };
+
+    let attachments: Vec<(i64, Vec<u8>)> = sqlx::query_as(
+        "
+    select
+        attachments.message_id,
+        attachments.data
+    from attachments
+    inner join messages
+        on messages.id = attachments.message_id
+    where messages.conversation_id = ?
+    order by attachments.id;
+    ",
+    )
+    .bind(conversation_id)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let mut images: HashMap<i64, Vec<String>> = HashMap::new();
+
+    for (message_id, data) in attachments {
+        images
+            .entry(message_id)
+            .or_default()
+            .push(BASE64_STANDARD.encode(data));
+    }
+
+    let mut body = ChatRequest::new(
+        model.name,
+        &system_prompt,
+        &messages,
+        &images,
+        options,
+        keep_alive,
+    );
+
+    if tools_enabled {
+        body.tools = tools.definitions();
+    }
+
+    body.format = format;
+
+    Ok((body, chat_backend))
+}
+
+const DEFAULT_CONCURRENCY: usize = 2;
+
+#[derive(Clone, Debug, Default)]
+struct Limits {
+    semaphores: Arc<Mutex<HashMap<i64, Arc<Semaphore>>>>,
+}
+
+impl Limits {
+    fn new(concurrency: impl IntoIterator<Item = (i64, usize)>) -> Self {
+        Limits {
+            semaphores: Arc::new(Mutex::new(
+                concurrency
+                    .into_iter()
+                    .map(|(backend_id, concurrency)| {
+                        (backend_id, Arc::new(Semaphore::new(concurrency)))
+                    })
+                    .collect(),
+            )),
+        }
+    }
+
+    async fn acquire(&self, backend_id: i64) -> anyhow::Result<OwnedSemaphorePermit> {
+        let semaphore = self
+            .semaphores
+            .lock()
+            .await
+            .entry(backend_id)
+            .or_insert_with(|| Arc::new(Semaphore::new(DEFAULT_CONCURRENCY)))
+            .clone();
+
+        Ok(semaphore.acquire_owned().await?)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, sqlx::Type)]
+#[sqlx(rename_all = "lowercase")]
+enum GenerationStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+    Interrupted,
+}
+
+#[derive(Clone, Debug)]
+struct GenerationQueue {
+    pool: sqlx::Pool<Sqlite>,
+    chat_backends: ChatBackends,
+    limits: Limits,
+    generations: Generations,
+    tools: Tools,
+    titles: Titles,
+}
+
+impl GenerationQueue {
+    async fn push(&self, ollama_response: &Message) -> anyhow::Result<()> {
+        let mut conn = self.pool.acquire().await?;
+
+        let queued: sqlx::Result<(i64, i64)> = sqlx::query_as(
+            "
+            insert into generations (message_id, backend_id)
+            select ?, models.backend_id
+            from conversations
+            inner join models
+                on models.id = conversations.model_id
+            where conversations.id = ?
+            returning id, backend_id;
+            ",
+        )
+        .bind(ollama_response.id)
+        .bind(ollama_response.conversation_id)
+        .fetch_one(&mut *conn)
+        .await;
+
+        let (generation_id, backend_id) = match queued {
+            Ok(queued) => queued,
+            Err(e) => {
+                let error = format!("could not queue the reply: {e}");
+                mark_message_failed(&mut conn, ollama_response.id, &error).await?;
+                anyhow::bail!(error);
+            }
+        };
+
+        drop(conn);
+
+        let (ollama_tx, ollama_rx) = GenerationTx::new();
+
+        spawn_llm_response_update_task(
+            self.pool.clone(),
+            ollama_response.id,
+            generation_id,
+            ollama_rx,
+            self.titles.clone(),
+            self.generations.clone(),
+            ollama_tx.clone(),
+        );
+
+        // hold the lock until the task is registered,
+        // so it can't be deregistered first
+        let mut generations_guard = self.generations.lock().await;
+
+        let queue = self.clone();
+        let reply = ollama_response.clone();
+        let tx = ollama_tx.clone();
+
+        let task = tokio::spawn(async move {
+            if let Err(e) = queue
+                .run(generation_id, backend_id, &reply, &ollama_tx)
+                .await
+            {
+                error!("error streaming chat response: {:?}", e);
+
+                ollama_tx.send(OllamaResponseMessage::Error {
+                    message_id: reply.id,
+                    error: e.to_string(),
+                });
+            }
+        });
+
+        generations_guard.insert(ollama_response.id, Generation { task, tx });
+
+        Ok(())
+    }
+
+    async fn run(
+        &self,
+        generation_id: i64,
+        backend_id: i64,
+        ollama_response: &Message,
+        ollama_tx: &GenerationTx,
+    ) -> anyhow::Result<()> {
+        let mut conn = self.pool.acquire().await?;
+
+        // before the reply waits for its backend, because a summary waits for it too
+        let (body, chat_backend) = chat_request(
+            &mut conn,
+            &self.chat_backends,
+            &self.limits,
+            &self.tools,
+            ollama_response,
+        )
+        .await?;
+
+        drop(conn);
+
+        // held until the reply is written, or stopped
+        let _permit = self.limits.acquire(backend_id).await?;
+
+        let mut conn = self.pool.acquire().await?;
+
+        sqlx::query(
+            "
+            update generations
+            set
+                status = ?,
+                started_at = STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')
+            where id = ?
+            ",
+        )
+        .bind(GenerationStatus::Running)
+        .bind(generation_id)
+        .execute(&mut *conn)
+        .await?;
+
+        drop(conn);
+
+        stream_chat_response_with_tools(
+            chat_backend.as_ref(),
+            body,
+            ollama_response.id,
+            ollama_tx,
+            &self.tools,
+        )
+        .await
+    }
+
+    async fn position(
+        conn: &mut sqlx::SqliteConnection,
+        message_id: i64,
+    ) -> sqlx::Result<Option<i64>> {
+        sqlx::query_scalar(
+            "
+            select (
+                select count(*)
+                from generations ahead
+                where ahead.backend_id = generations.backend_id
+                and ahead.status = ?
+                and ahead.id < generations.id
+            )
+            from generations
+            where message_id = ?
+            and status = ?
+            order by id desc
+            limit 1;
+            ",
+        )
+        .bind(GenerationStatus::Queued)
+        .bind(message_id)
+        .bind(GenerationStatus::Queued)
+        .fetch_optional(conn)
+        .await
+    }
+}
+
+async fn mark_message_failed(
+    conn: &mut sqlx::SqliteConnection,
+    message_id: i64,
+    error: &str,
+) -> sqlx::Result<()> {
+    sqlx::query(
+        "
+        update messages
+        set
+            status = ?,
+            error = ?,
+            updated_at = STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')
+        where id = ?
+        ",
+    )
+    .bind(MessageStatus::Failed)
+    .bind(error)
+    .bind(message_id)
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+fn message_row(index: usize, message: &Message) -> Markup {
+    html! {
+        // so they can be swapped out along with the reply
+        tr class=[message.reply_first_version_id.map(|id| format!("tools-of-{id}"))] {
+            td {
+                (index)
+            }
+            td {
+                (message.inserted_at)
+            }
+            td {
+                (message.who.to_string())
+                @if message.pinned {
+                    br;
+                    span class="tag is-info is-light" {
+                        "pinned"
+                    }
+                }
+            }
+            td {
+                @if let Some(tool_name) = &message.tool_name {
+                    p class="help" {
+                        (tool_name)
+                    }
+                }
+                @if !message.thinking.is_empty() {
+                    (thinking_block(&message.thinking, false))
+                }
+                pre {
+                    @if message.json_valid == Some(true) {
+                        (pretty_json(&message.body))
+                    } @else {
+                        (message.body)
+                    }
+                }
+                @if let Some(json_valid) = message.json_valid {
+                    (json_check(json_valid, message.json_error.as_deref()))
+                }
+                (attachment_thumbnails(message))
+                @if message.status == MessageStatus::Generating {
+                    p class="help" {
+                        "still being written, reload to see more"
+                    }
+                }
+                @if message.status == MessageStatus::Stopped {
+                    (stopped_marker())
+                }
+                @if let Some(error) = &message.error {
+                    (message_error(message.id, error))
+                }
+                (generation_stats(&message.metadata))
+            }
+            td {
+                div {
+                    a hx-post=(format!("/conversations/{}/fork/{}", message.conversation_id, message.id)) {
+                        "Fork"
+                    }
+                }
+                @if message.who == Who::Me {
+                    div {
+                        a
+                            hx-get=(format!("/messages/{}/edit", message.id))
+                            hx-target="closest tr"
+                            hx-swap="outerHTML"
+                        {
+                            "Edit"
+                        }
+                    }
+                }
+                div {
+                    a
+                        hx-post=(format!("/messages/{}/pin", message.id))
+                        hx-target="closest tr"
+                        hx-swap="outerHTML"
+                    {
+                        @if message.pinned {
+                            "Unpin"
+                        } @else {
+                            "Pin"
+                        }
+                    }
+                }
+                @if message.who == Who::Llama && message.status != MessageStatus::Generating {
+                    div {
+                        a
+                            hx-post=(format!("/messages/{}/regenerate", message.id))
+                            hx-target="closest tr"
+                            hx-swap="outerHTML"
+                        {
+                            "Regenerate"
+                        }
+                    }
+                }
+                @if message.version_count > 1 {
+                    div class="is-flex" {
+                        a
+                            hx-post=(format!("/messages/{}/versions/previous", message.id))
+                            hx-target="closest tr"
+                            hx-swap="outerHTML"
+                        {
+                            "‹"
+                        }
+                        span class="mx-1" {
+                            (message.version_number) "/" (message.version_count)
+                        }
+                        a
+                            hx-post=(format!("/messages/{}/versions/next", message.id))
+                            hx-target="closest tr"
+                            hx-swap="outerHTML"
+                        {
+                            "›"
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn thinking_block(thinking: &str, open: bool) -> Markup {
+    html! {
+        details class="thinking mb-2" open[open] {
+            summary class="has-text-grey" {
+                "Thinking"
+            }
+            pre class="has-text-grey" {
+                (thinking)
+            }
+        }
+    }
+}
+
+fn context_cut_marker(summary: Option<&str>) -> Markup {
+    html! {
+        tr {
+            td colspan="5" class="has-text-centered has-text-grey" {
+                @if let Some(summary) = summary {
+                    details {
+                        summary {
+                            "— the messages above were summarized for the model —"
+                        }
+                        pre class="has-text-left" {
+                            (summary)
+                        }
+                    }
+                } @else {
+                    "— the messages above no longer fit, and were not sent to the model —"
+                }
+            }
+        }
+    }
+}
+
+fn pretty_json(json: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(json)
+        .and_then(|json| serde_json::to_string_pretty(&json))
+        .unwrap_or_else(|_| json.to_string())
+}
+
+fn json_check(json_valid: bool, json_error: Option<&str>) -> Markup {
+    html! {
+        @if json_valid {
+            span class="tag is-success is-light" {
+                "valid JSON"
+            }
+        } @else {
+            span class="tag is-danger" {
+                "invalid JSON"
+            }
+            @if let Some(json_error) = json_error {
+                pre class="help is-danger" {
+                    (json_error)
+                }
+            }
+        }
+    }
+}
+
+fn attachment_thumbnails(message: &Message) -> Markup {
+    html! {
+        @if message.attachment_ids.is_some() {
+            div class="is-flex mt-2" {
+                @for attachment_id in message.attachment_ids() {
+                    a class="mr-2" href=(format!("/attachments/{attachment_id}")) target="_blank" {
+                        img
+                            src=(format!("/attachments/{attachment_id}"))
+                            style="max-height: 8rem; max-width: 12rem;";
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn attachments_show(
+    State(state): State<Arc<AppState>>,
+    Path(attachment_id): Path<i64>,
+) -> axum::response::Result<impl IntoResponse> {
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    let (data,): (Vec<u8>,) = sqlx::query_as(
+        "
+        select data
+        from attachments
+        where id = ?
+        limit 1;
+        ",
+    )
+    .bind(attachment_id)
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    // sniffed again rather than trusting the stored type,
+    // so anything stored before uploads were checked is only ever downloaded
+    let content_type = image_content_type(&data).unwrap_or("application/octet-stream");
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+            (header::CONTENT_SECURITY_POLICY, "sandbox"),
+        ],
+        data,
+    ))
+}
+
+fn streaming_message_row(index: usize, message: &Message, tool_message: i64) -> Markup {
+    let offset = ReplyOffset {
+        body: message.body.len(),
+        thinking: message.thinking.len(),
+        tool_message,
+    };
+
+    html! {
+        tr {
+            td {
+                (index)
+            }
+            td {
+                (message.inserted_at)
+            }
+            td {
+                (message.who.to_string())
+            }
+            td {
+                (thinking_block(&message.thinking, true))
+                // TODO
+                // document what this whole thing does...
+                div
+                    hx-ext="sse"
+                    sse-connect=(format!(
+                        "/messages/{}/sse?body={}&thinking={}&tool_message={}",
+                        message.id, offset.body, offset.thinking, offset.tool_message
+                    ))
+                    sse-swap="ChatData"
+                    hx-target="next"
+                    hx-swap="beforeend"
+                {
+                    div
+                    hx-get="/empty"
+                    hx-trigger="sse:ChatDone, sse:ChatError"
+                    hx-target="closest [sse-connect]"
+                    hx-swap="delete" {}
+                    // puts the error after the partial reply
+                    div
+                    sse-swap="ChatError"
+                    hx-target="closest td"
+                    hx-swap="beforeend" {}
+                    // reasoning goes in its own block, above the reply
+                    div
+                    sse-swap="ChatThinking"
+                    hx-target="previous .thinking pre"
+                    hx-swap="beforeend" {}
+                    // whether a JSON reply is valid
+                    div
+                    sse-swap="ChatDone"
+                    hx-target="closest td"
+                    hx-swap="beforeend" {}
+                    // tool calls go before the reply, which carries on after them
+                    div
+                    sse-swap="ChatToolCall"
+                    hx-target="closest tr"
+                    hx-swap="beforebegin" {}
+                    // where it is in line, while its backend is busy with other replies
+                    div
+                    hx-get=(format!("/messages/{}/queue", message.id))
+                    hx-trigger="load, every 2s"
+                    hx-target="this"
+                    hx-swap="innerHTML" {}
+                    button
+                        class="button is-small is-danger is-light mb-2"
+                        hx-post=(format!("/messages/{}/stop", message.id))
+                        hx-target="closest td"
+                        hx-swap="beforeend"
+                    {
+                        "Stop"
+                    }
+                }
+                // javascript removes this class when the llm is done responding
+                pre class="llm-response" {
+                    (message.body)
+                    (FILLED_BLOCK)
+                }
+            }
+            td {
+                a hx-post=(format!("/conversations/{}/fork/{}", message.conversation_id, message.id)) {
+                    "Fork"
+                }
+            }
+        }
+    }
+}
+
+fn stopped_marker() -> Markup {
+    html! {
+        p class="help" {
+            "stopped by you"
+        }
+    }
+}
+
+fn message_error(message_id: i64, error: &str) -> Markup {
+    html! {
+        p class="help is-danger" {
+            (error)
+        }
+        a
+            hx-post=(format!("/messages/{message_id}/retry"))
+            hx-target="closest tr"
+            hx-swap="outerHTML"
+        {
+            "Retry"
+        }
+    }
+}
+
+async fn finish_generation(
+    conn: &mut sqlx::SqliteConnection,
+    generation_id: i64,
+    status: GenerationStatus,
+) -> sqlx::Result<()> {
+    sqlx::query(
+        "
+        update generations
+        set
+            status = ?,
+            finished_at = STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')
+        where id = ?
+        ",
+    )
+    .bind(status)
+    .bind(generation_id)
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+const FLUSH_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+const FLUSH_BYTES: usize = 4096;
+
+async fn store_written(
+    pool: &sqlx::Pool<Sqlite>,
+    message_id: i64,
+    ollama_tx: &GenerationTx,
+) -> sqlx::Result<()> {
+    let (body, thinking) = ollama_tx.text();
+
+    sqlx::query(
+        "
+        update messages
+        set
+            body = ?,
+            thinking = ?
+        where id = ?
+        ",
+    )
+    .bind(body)
+    .bind(thinking)
+    .bind(message_id)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+async fn store_end(
+    pool: &sqlx::Pool<Sqlite>,
+    message_id: i64,
+    generation_id: i64,
+    ollama_tx: &GenerationTx,
+    end: OllamaResponseMessage,
+) -> sqlx::Result<()> {
+    store_written(pool, message_id, ollama_tx).await?;
+
+    let mut conn = pool.acquire().await?;
+
+    match end {
+        OllamaResponseMessage::Done {
+            metadata,
+            format_check,
+        } => {
+            sqlx::query(
+                "
+                update messages
+                set
+                    status = ?,
+                    json_valid = ?,
+                    json_error = ?,
+                    model = ?,
+                    created_at = ?,
+                    total_duration = ?,
+                    load_duration = ?,
+                    prompt_eval_count = ?,
+                    prompt_eval_duration = ?,
+                    eval_count = ?,
+                    eval_duration = ?,
+                    done_reason = ?
+                where id = ?
+                ",
+            )
+            .bind(MessageStatus::Done)
+            .bind(
+                format_check
+                    .as_ref()
+                    .map(|format_check| format_check.is_ok()),
+            )
+            .bind(format_check.and_then(|format_check| format_check.err()))
+            .bind(metadata.model)
+            .bind(metadata.created_at)
+            .bind(metadata.total_duration)
+            .bind(metadata.load_duration)
+            .bind(metadata.prompt_eval_count)
+            .bind(metadata.prompt_eval_duration)
+            .bind(metadata.eval_count)
+            .bind(metadata.eval_duration)
+            .bind(metadata.done_reason)
+            .bind(message_id)
+            .execute(&mut *conn)
+            .await?;
+
+            finish_generation(&mut conn, generation_id, GenerationStatus::Done).await?;
+        }
+        OllamaResponseMessage::Stopped => {
+            sqlx::query(
+                "
+                update messages
+                set
+                    status = ?,
+                    updated_at = STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')
+                where id = ?
+                ",
+            )
+            .bind(MessageStatus::Stopped)
+            .bind(message_id)
+            .execute(&mut *conn)
+            .await?;
+
+            finish_generation(&mut conn, generation_id, GenerationStatus::Cancelled).await?;
+        }
+        OllamaResponseMessage::Error { error, .. } => {
+            mark_message_failed(&mut conn, message_id, &error).await?;
+
+            finish_generation(&mut conn, generation_id, GenerationStatus::Failed).await?;
+        }
+        // not an end
+        OllamaResponseMessage::More { .. }
+        | OllamaResponseMessage::Thinking { .. }
+        | OllamaResponseMessage::ToolCalls { .. } => (),
+    }
+
+    Ok(())
+}
+
+fn spawn_llm_response_update_task(
+    pool: sqlx::Pool<Sqlite>,
+    ollama_response_message_id: i64,
+    generation_id: i64,
+    mut ollama_rx: broadcast::Receiver<OllamaResponseMessage>,
+    titles: Titles,
+    generations: Generations,
+    ollama_tx: GenerationTx,
+) {
+    tokio::spawn(async move {
+        let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+
+        // whether there's text that hasn't been stored yet, and how much of it we know of
+        let mut unstored = false;
+        let mut unstored_bytes = 0;
+
+        loop {
+            let chat_chunk = tokio::select! {
+                chat_chunk = ollama_rx.recv() => chat_chunk,
+                _ = flush.tick() => {
+                    if unstored {
+                        match store_written(&pool, ollama_response_message_id, &ollama_tx).await {
+                            Ok(()) => {
+                                unstored = false;
+                                unstored_bytes = 0;
+                            }
+                            Err(e) => error!("could not store reply {}: {:?}", ollama_response_message_id, e),
+                        }
+                    }
+
+                    continue;
+                }
+            };
+
+            match chat_chunk {
+                Ok(OllamaResponseMessage::More { response }) => unstored_bytes += response.len(),
+                Ok(OllamaResponseMessage::Thinking { thinking }) => {
+                    unstored_bytes += thinking.len()
+                }
+                // already stored by the generation
+                Ok(OllamaResponseMessage::ToolCalls { .. }) => continue,
+                Ok(end) => {
+                    let done = matches!(end, OllamaResponseMessage::Done { .. });
+
+                    match store_end(
+                        &pool,
+                        ollama_response_message_id,
+                        generation_id,
+                        &ollama_tx,
+                        end,
+                    )
+                    .await
+                    {
+                        Ok(()) if done => {
+                            tokio::spawn(async move {
+                                if let Err(e) = titles.write(ollama_response_message_id).await {
+                                    warn!("could not title the conversation: {:?}", e);
+                                }
+                            });
+                        }
+                        Ok(()) => (),
+                        Err(e) => error!(
+                            "could not store the end of reply {}: {:?}",
+                            ollama_response_message_id, e
+                        ),
+                    }
+
+                    break;
+                }
+                // what was missed is still in what the reply has written, which is what's stored
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    debug!(
+                        "storing reply {} fell {} chunks behind",
+                        ollama_response_message_id, skipped
+                    );
+                    unstored_bytes = FLUSH_BYTES;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+
+            unstored = true;
+
+            if unstored_bytes >= FLUSH_BYTES {
+                match store_written(&pool, ollama_response_message_id, &ollama_tx).await {
+                    Ok(()) => {
+                        unstored = false;
+                        unstored_bytes = 0;
+                    }
+                    Err(e) => error!(
+                        "could not store reply {}: {:?}",
+                        ollama_response_message_id, e
+                    ),
+                }
+            }
+        }
+
+        // only now is everything in the database, for anyone who starts watching after this.
+        // unless it was stopped, and another generation has started writing the message since
+        let mut generations = generations.lock().await;
+
+        if generations
+            .get(&ollama_response_message_id)
+            .is_some_and(|generation| generation.tx.same_channel(&ollama_tx))
+        {
+            generations.remove(&ollama_response_message_id);
+        }
+    });
+}
+
+async fn messages_sse(
+    State(state): State<Arc<AppState>>,
+    Path(message_id): Path<i64>,
+    Query(offset): Query<ReplyOffset>,
+    headers: HeaderMap,
+) -> axum::response::Result<axum::response::Response> {
+    let last_event_id: Option<ReplyOffset> = headers
+        .get("Last-Event-ID")
+        .and_then(|last_event_id| last_event_id.to_str().ok())
+        .and_then(|last_event_id| last_event_id.parse().ok());
+
+    let pool = state.pool.clone();
+    let generations = state.generations.clone();
+
+    let ollama_tx = generations
+        .lock()
+        .await
+        .get(&message_id)
+        .map(|generation| generation.tx.clone());
+
+    // it finished before we got here, so the rest of it is in the database
+    let Some(ollama_tx) = ollama_tx else {
+        let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+
+        let message = get_message(&mut conn, message_id)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let end = match message.status {
+            MessageStatus::Failed => OllamaResponseMessage::Error {
+                message_id,
+                error: message.error.unwrap_or_default(),
+            },
+            _ => OllamaResponseMessage::Done {
+                metadata: GenerationMetadata::default(),
+                format_check: message.json_valid.map(|json_valid| {
+                    if json_valid {
+                        Ok(())
+                    } else {
+                        Err(message.json_error.unwrap_or_default())
+                    }
+                }),
+            },
+        };
+
+        let written = Written {
+            body: message.body,
+            thinking: message.thinking,
+            tool_messages: vec![],
+            end: Some(end),
+        };
+
+        let offset = last_event_id
+            .filter(|&last_event_id| written.has_reached(last_event_id))
+            .unwrap_or(offset);
+
+        let sse_stream = reply_events(futures::stream::iter(written.since(offset)), offset)
+            .chain(futures::stream::pending());
+
+        return Ok(Sse::new(sse_stream).into_response());
+    };
+
+    let offset = last_event_id
+        .filter(|&last_event_id| ollama_tx.has_reached(last_event_id))
+        .unwrap_or(offset);
+
+    let sse_stream = reply_events(ollama_tx.watch(offset), offset);
+
+    Ok(Sse::new(sse_stream)
+        .keep_alive(
+            axum::response::sse::KeepAlive::new()
+                .interval(std::time::Duration::from_secs(1))
+                .text("keep-alive-text"),
+        )
+        .into_response())
+}
+
+fn reply_events(
+    chat_chunks: impl Stream<Item = OllamaResponseMessage>,
+    mut offset: ReplyOffset,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    chat_chunks.filter_map(move |chat_chunk| {
+        let chat_chunk = offset.advance(chat_chunk)?;
+        Some(Ok(chat_event(chat_chunk).id(offset.to_string())))
+    })
+}
+
+fn chat_event(chat_chunk: OllamaResponseMessage) -> Event {
+    match chat_chunk {
+        OllamaResponseMessage::More { response } => {
+            // it's swapped in as html, so it has to be escaped
+            let mut response = html! { (response) }.into_string();
+            // SSE can't carry carriage returns
+            response.retain(|c| c != '\r');
+            response.push(FILLED_BLOCK);
+            Event::default().event("ChatData").data(response)
+        }
+        OllamaResponseMessage::Thinking { thinking } => {
+            let mut thinking = html! { (thinking) }.into_string();
+            thinking.retain(|c| c != '\r');
+            Event::default().event("ChatThinking").data(thinking)
+        }
+        OllamaResponseMessage::Done { format_check, .. } => {
+            debug!("Sending 'Done' SSE message");
+            Event::default().event("ChatDone").data(
+                format_check
+                    .map(|format_check| {
+                        json_check(format_check.is_ok(), format_check.err().as_deref())
+                            .into_string()
+                            .replace('\r', "")
+                    })
+                    .unwrap_or_default(),
+            )
+        }
+        OllamaResponseMessage::Stopped => {
+            debug!("Sending 'Done' SSE message");
+            Event::default().event("ChatDone").data("")
+        }
+        OllamaResponseMessage::ToolCalls { messages } => {
+            Event::default().event("ChatToolCall").data(
+                html! {
+                    @for (index, message) in messages.iter() {
+                        (message_row(*index, message))
+                    }
+                }
+                .into_string()
+                .replace('\r', ""),
+            )
+        }
+        OllamaResponseMessage::Error { message_id, error } => {
+            debug!("Sending 'Error' SSE message");
+            Event::default().event("ChatError").data(
+                message_error(message_id, &error)
+                    .into_string()
+                    .replace('\r', ""),
+            )
+        }
+    }
+}
+
+async fn conversations_create(
+    State(state): State<Arc<AppState>>,
+) -> axum::response::Result<HeaderMap> {
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    let conversation_id: (i64,) =
+        sqlx::query_as("insert into conversations (name) values (?) returning id;")
+            .bind(DEFAULT_CONVERSATION_NAME)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(|e| e.to_string())?;
+
+    let conversation_id = conversation_id.0;
+
+    let path = format!("/conversations/{conversation_id}");
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "HX-Redirect",
+        HeaderValue::try_from(path).map_err(|e| e.to_string())?,
+    );
+
+    Ok(headers)
+}
+
+async fn conversations_fork_create(
+    State(state): State<Arc<AppState>>,
+    Path((conversation_id, message_id)): Path<(i64, i64)>,
+) -> axum::response::Result<HeaderMap> {
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+    let mut tx = conn.begin().await.map_err(|e| e.to_string())?;
+
+    let new_conversation_id =
+        fork_conversation(&mut tx, conversation_id, ForkPoint::Including(message_id))
+            .await
+            .map_err(|e| e.to_string())?;
+
+    tx.commit().await.map_err(|e| e.to_string())?;
+
+    let path = format!("/conversations/{new_conversation_id}");
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "HX-Redirect",
+        HeaderValue::try_from(path).map_err(|e| e.to_string())?,
+    );
+
+    Ok(headers)
+}
+
+enum ForkPoint {
+    Including(i64),
+    Before(i64),
+    End,
+}
+
+async fn fork_conversation(
+    conn: &mut sqlx::SqliteConnection,
+    conversation_id: i64,
+    fork_point: ForkPoint,
+) -> sqlx::Result<i64> {
+    let (new_conversation_id,): (i64,) = sqlx::query_as(
+        "
+        insert into conversations (
+            name,
+            source_conversation_id,
+            model_id,
+            system_prompt,
+            keep_alive,
+            tools_enabled,
+            response_format,
+            json_schema,
+            context_strategy,
+            temperature,
+            top_p,
+            top_k,
+            num_ctx,
+            seed,
+            repeat_penalty,
+            stop,
+            num_predict
+        )
+        select
+            ?,
+            id,
+            model_id,
+            system_prompt,
+            keep_alive,
+            tools_enabled,
+            response_format,
+            json_schema,
+            context_strategy,
+            temperature,
+            top_p,
+            top_k,
+            num_ctx,
+            seed,
+            repeat_penalty,
+            stop,
+            num_predict
+        from conversations
+        where id = ?
+        returning id;",
+    )
+    .bind(DEFAULT_CONVERSATION_NAME)
+    .bind(conversation_id)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    let (latest_message, comparison) = match fork_point {
+        ForkPoint::Including(message_id) => (Some(get_message(conn, message_id).await?), "<="),
+        ForkPoint::Before(message_id) => (Some(get_message(conn, message_id).await?), "<"),
+        ForkPoint::End => {
+            let latest_message: Option<Message> = sqlx::query_as(
+                "
+            select
+                id,
+                body,
+                who,
+                conversation_id,
+                inserted_at,
+                status,
+                error,
+                version_of,
+                reply_id,
+                reply_first_version_id
+            from messages
+            where conversation_id = ?
+            and canonical = 1
+            order by
+                inserted_at desc,
+                coalesce(reply_first_version_id, version_of, id) desc,
+                reply_id is null desc,
+                id desc
+            limit 1;
+            ",
+            )
+            .bind(conversation_id)
+            .fetch_optional(&mut *conn)
+            .await?;
+
+            (latest_message, "<=")
+        }
+    };
+
+    // a reply's tool calls and results go wherever the reply does
+    let message_ids: Vec<(i64, Option<i64>)> = match latest_message {
+        Some(latest_message) => {
+            sqlx::query_as(&format!(
+                "
+            select
+                id,
+                reply_id
+            from messages
+            where conversation_id = ?
+            and canonical = 1
+            and (inserted_at, coalesce(reply_first_version_id, version_of, id)) {comparison} (?, ?)
+            order by inserted_at, coalesce(reply_first_version_id, version_of, id), reply_id is null, id
+            "
+            ))
+            .bind(latest_message.conversation_id)
+            .bind(&latest_message.inserted_at)
+            .bind(latest_message.position_id())
+            .fetch_all(&mut *conn)
+            .await?
+        }
+        None => vec![],
+    };
+
+    let mut copies = vec![];
+
+    // one at a time, so they keep their order and their images
+    for (message_id, reply_id) in message_ids {
+        let (new_message_id,): (i64,) = sqlx::query_as(
+            "
+        insert into messages (who, body, tool_name, pinned, conversation_id)
+        select
+            who,
+            body,
+            tool_name,
+            pinned,
+            ?
+        from messages
+        where id = ?
+        returning id;
+        ",
+        )
+        .bind(new_conversation_id)
+        .bind(message_id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+        copy_attachments(conn, message_id, new_message_id).await?;
+
+        copies.push((message_id, new_message_id, reply_id));
+    }
+
+    link_copied_tool_messages(conn, &copies).await?;
+
+    Ok(new_conversation_id)
+}
+
+async fn link_copied_tool_messages(
+    conn: &mut sqlx::SqliteConnection,
+    copies: &[(i64, i64, Option<i64>)],
+) -> sqlx::Result<()> {
+    for (_, copy_id, reply_id) in copies {
+        let Some(reply_id) = reply_id else {
+            continue;
+        };
+
+        let Some((_, reply_copy_id, _)) = copies.iter().find(|(id, _, _)| id == reply_id) else {
+            continue;
+        };
+
+        sqlx::query(
+            "
+        update messages
+        set
+            reply_id = ?,
+            reply_first_version_id = ?,
+            inserted_at = (select inserted_at from messages where id = ?)
+        where id = ?
+        ",
+        )
+        .bind(reply_copy_id)
+        .bind(reply_copy_id)
+        .bind(reply_copy_id)
+        .bind(copy_id)
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+async fn copy_attachments(
+    conn: &mut sqlx::SqliteConnection,
+    from_message_id: i64,
+    to_message_id: i64,
+) -> sqlx::Result<()> {
+    sqlx::query(
+        "
+    insert into attachments (message_id, content_type, data)
+    select
+        ?,
+        content_type,
+        data
+    from attachments
+    where message_id = ?
+    order by id
+    ",
+    )
+    .bind(to_message_id)
+    .bind(from_message_id)
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+async fn conversations_compare_create(
+    State(state): State<Arc<AppState>>,
+    Path(conversation_id): Path<i64>,
+    // `model_id` is repeated, once per checked model
+    Form(form): Form<Vec<(String, String)>>,
+) -> axum::response::Result<HeaderMap> {
+    let mut prompt = String::new();
+    let mut model_ids = vec![];
+
+    for (name, value) in form {
+        match name.as_str() {
+            "body" => prompt = value,
+            "model_id" => model_ids.push(
+                value
+                    .parse::<i64>()
+                    .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()))?,
+            ),
+            _ => {}
+        }
+    }
+
+    if prompt.trim().is_empty() {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "ask the models something to compare them",
+        )
+            .into());
+    }
+
+    if model_ids.len() < 2 {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "pick at least two models to compare",
+        )
+            .into());
+    }
+
+    let pool = state.pool.clone();
+    let queue = state.queue.clone();
+
+    let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+
+    let mut txn = conn.begin().await.map_err(|e| e.to_string())?;
+
+    // the forks would get what's been written so far as if it were the whole reply
+    let (generating,): (bool,) = sqlx::query_as(
+        "
+    select count(*) > 0
+    from messages
+    where conversation_id = ?
+    and status = ?;
+    ",
+    )
+    .bind(conversation_id)
+    .bind(MessageStatus::Generating)
+    .fetch_one(&mut *txn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    if generating {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "wait for the reply to finish before comparing",
+        )
+            .into());
+    }
+
+    let (comparison_id,): (i64,) = sqlx::query_as(
+        "
+    insert into comparisons (conversation_id, prompt)
+    values (?, ?)
+    returning id;
+    ",
+    )
+    .bind(conversation_id)
+    .bind(&prompt)
+    .fetch_one(&mut *txn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let mut replies = vec![];
+
+    for model_id in model_ids {
+        let model = get_model(&mut txn, model_id)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let fork_id = fork_conversation(&mut txn, conversation_id, ForkPoint::End)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        sqlx::query(
+            "
+        update conversations
+        set
+            model_id = ?,
+            name = (select name from conversations where id = ?) || ' (' || ? || ')'
+        where id = ?;
+        ",
+        )
+        .bind(model.id)
+        .bind(conversation_id)
+        .bind(&model.name)
+        .bind(fork_id)
+        .execute(&mut *txn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let (prompt_message_id,): (i64,) = sqlx::query_as(
+            "
+        insert into messages (who, body, conversation_id)
+        values (?, ?, ?)
+        returning id;
+        ",
+        )
+        .bind(Who::Me)
+        .bind(&prompt)
+        .bind(fork_id)
+        .fetch_one(&mut *txn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        sqlx::query(
+            "
+        insert into comparison_forks (comparison_id, conversation_id, prompt_message_id)
+        values (?, ?, ?);
+        ",
+        )
+        .bind(comparison_id)
+        .bind(fork_id)
+        .bind(prompt_message_id)
+        .execute(&mut *txn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let reply: Message = sqlx::query_as(
+            "
+        insert into messages (who, body, conversation_id, status)
+        values (?, ?, ?, ?)
+        returning *;
+        ",
+        )
+        .bind(Who::Llama)
+        .bind("")
+        .bind(fork_id)
+        .bind(MessageStatus::Generating)
+        .fetch_one(&mut *txn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        replies.push(reply);
+    }
+
+    txn.commit().await.map_err(|e| e.to_string())?;
+
+    for reply in replies.iter() {
+        // so the other models can carry on
+        if let Err(e) = queue.push(reply).await {
+            error!("could not start comparison reply {}: {:?}", reply.id, e);
+        }
+    }
+
+    let path = format!("/comparisons/{comparison_id}");
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "HX-Redirect",
+        HeaderValue::try_from(path).map_err(|e| e.to_string())?,
+    );
+
+    Ok(headers)
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct ComparisonFork {
+    conversation_id: i64,
+    model_name: String,
+    backend_name: String,
+}
+
+async fn comparisons_show(
+    State(state): State<Arc<AppState>>,
+    Path(comparison_id): Path<i64>,
+) -> axum::response::Result<Markup> {
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    let (conversation_id, conversation_name, prompt): (i64, String, String) = sqlx::query_as(
+        "
+    select
+        comparisons.conversation_id,
+        conversations.name,
+        comparisons.prompt
+    from comparisons
+    inner join conversations
+        on conversations.id = comparisons.conversation_id
+    where comparisons.id = ?
+    limit 1;
+    ",
+    )
+    .bind(comparison_id)
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let forks: Vec<ComparisonFork> = sqlx::query_as(
+        "
+    select
+        comparison_forks.conversation_id,
+        models.name as model_name,
+        backends.name as backend_name
+    from comparison_forks
+    inner join conversations
+        on conversations.id = comparison_forks.conversation_id
+    inner join models
+        on models.id = conversations.model_id
+    inner join backends
+        on backends.id = models.backend_id
+    where comparison_forks.comparison_id = ?
+    order by comparison_forks.conversation_id;
+    ",
+    )
+    .bind(comparison_id)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let mut replies = vec![];
+
+    for fork in forks.iter() {
+        let reply_id: Option<(i64,)> = sqlx::query_as(
+            "
+        select id
+        from messages
+        where conversation_id = ?
+        and who = ?
+        and canonical = 1
+        order by inserted_at desc, coalesce(version_of, id) desc
+        limit 1;
+        ",
+        )
+        .bind(fork.conversation_id)
+        .bind(Who::Llama)
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let reply = match reply_id {
+            Some((reply_id,)) => {
+                let reply = get_message(&mut conn, reply_id)
+                    .await
+                    .map_err(|e| e.to_string())?;
+
+                let position = message_position(&mut conn, &reply)
+                    .await
+                    .map_err(|e| e.to_string())?;
+
+                Some((position, reply))
+            }
+            None => None,
+        };
+
+        replies.push(reply);
+    }
+
+    let with_backend = forks
+        .iter()
+        .any(|fork| fork.backend_name != forks[0].backend_name);
+
+    Ok(layout! {
+        html! {
+            div class="container is-fluid mb-5" {
+                section class="section" {
+                    a href=(format!("/conversations/{conversation_id}")) {
+                        "Back to " (conversation_name)
+                    }
+                    h1 class="title" {
+                        "Comparing " (forks.len()) " models"
+                    }
+                    pre class="mb-5" {
+                        (prompt)
+                    }
+
+                    div class="columns" {
+                        @for (fork, reply) in forks.iter().zip(replies.iter()) {
+                            div class="column" {
+                                h2 class="subtitle" {
+                                    a href=(format!("/conversations/{}", fork.conversation_id)) {
+                                        (fork.model_name)
+                                        @if with_backend {
+                                            " (" (fork.backend_name) ")"
+                                        }
+                                    }
+                                }
+                                table class="table" {
+                                    tbody {
+                                        @match reply {
+                                            Some((position, reply)) if reply.status == MessageStatus::Generating => {
+                                                (streaming_message_row(*position, reply, 0))
+                                            }
+                                            Some((position, reply)) => {
+                                                (message_row(*position, reply))
+                                            }
+                                            None => {}
+                                        }
+                                    }
+                                }
+                                button
+                                    class="button is-link is-small"
+                                    hx-post=(format!("/comparisons/{comparison_id}/pick/{}", fork.conversation_id))
+                                    hx-confirm=(format!("Carry on with {}'s answer, and discard the others?", fork.model_name))
+                                {
+                                    "Continue with this answer"
+                                }
+                            }
+                        }
+                    }
+
+                    button
+                        class="button is-small"
+                        hx-post=(format!("/comparisons/{comparison_id}/keep"))
+                    {
+                        "Keep every answer as its own conversation"
+                    }
+                }
+            }
+        }
+    })
+}
+
+async fn comparisons_pick(
+    State(state): State<Arc<AppState>>,
+    Path((comparison_id, fork_id)): Path<(i64, i64)>,
+) -> axum::response::Result<HeaderMap> {
+    let pool = state.pool.clone();
+    let generations = state.generations.clone();
+
+    let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+
+    let mut txn = conn.begin().await.map_err(|e| e.to_string())?;
+
+    let (conversation_id, prompt_message_id): (i64, i64) = sqlx::query_as(
+        "
+    select
+        comparisons.conversation_id,
+        comparison_forks.prompt_message_id
+    from comparisons
+    inner join comparison_forks
+        on comparison_forks.comparison_id = comparisons.id
+    where comparisons.id = ?
+    and comparison_forks.conversation_id = ?
+    limit 1;
+    ",
+    )
+    .bind(comparison_id)
+    .bind(fork_id)
+    .fetch_one(&mut *txn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let (generating,): (bool,) = sqlx::query_as(
+        "
+    select count(*) > 0
+    from messages
+    where conversation_id = ?
+    and status = ?;
+    ",
+    )
+    .bind(fork_id)
+    .bind(MessageStatus::Generating)
+    .fetch_one(&mut *txn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    if generating {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "wait for this answer to finish first",
+        )
+            .into());
+    }
+
+    let prompt_message = get_message(&mut txn, prompt_message_id)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // the prompt, the answer, and any tools called along the way
+    let message_ids: Vec<(i64, Option<i64>)> = sqlx::query_as(
+        "
+    select
+        id,
+        reply_id
+    from messages
+    where conversation_id = ?
+    and canonical = 1
+    and (inserted_at, coalesce(reply_first_version_id, version_of, id)) >= (?, ?)
+    order by inserted_at, coalesce(reply_first_version_id, version_of, id), reply_id is null, id;
+    ",
+    )
+    .bind(fork_id)
+    .bind(&prompt_message.inserted_at)
+    .bind(prompt_message.position_id())
+    .fetch_all(&mut *txn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let mut copies = vec![];
+
+    for (message_id, reply_id) in message_ids {
+        let (new_message_id,): (i64,) = sqlx::query_as(
+            "
+        insert into messages (
+            who,
+            body,
+            thinking,
+            tool_name,
+            pinned,
+            status,
+            error,
+            json_valid,
+            json_error,
+            model,
+            created_at,
+            total_duration,
+            load_duration,
+            prompt_eval_count,
+            prompt_eval_duration,
+            eval_count,
+            eval_duration,
+            done_reason,
+            conversation_id
+        )
+        select
+            who,
+            body,
+            thinking,
+            tool_name,
+            pinned,
+            status,
+            error,
+            json_valid,
+            json_error,
+            model,
+            created_at,
+            total_duration,
+            load_duration,
+            prompt_eval_count,
+            prompt_eval_duration,
+            eval_count,
+            eval_duration,
+            done_reason,
+            ?
+        from messages
+        where id = ?
+        returning id;
+        ",
+        )
+        .bind(conversation_id)
+        .bind(message_id)
+        .fetch_one(&mut *txn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        copy_attachments(&mut txn, message_id, new_message_id)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        copies.push((message_id, new_message_id, reply_id));
+    }
+
+    link_copied_tool_messages(&mut txn, &copies)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    sqlx::query(
+        "
+    update conversations
+    set model_id = (select model_id from conversations where id = ?)
+    where id = ?;
+    ",
+    )
+    .bind(fork_id)
+    .bind(conversation_id)
+    .execute(&mut *txn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    // the other models may still be answering
+    let still_generating: Vec<(i64,)> = sqlx::query_as(
+        "
+    select messages.id
+    from messages
+    inner join comparison_forks
+        on comparison_forks.conversation_id = messages.conversation_id
+    where comparison_forks.comparison_id = ?
+    and messages.status = ?;
+    ",
+    )
+    .bind(comparison_id)
+    .bind(MessageStatus::Generating)
+    .fetch_all(&mut *txn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    sqlx::query(
+        "
+    delete from conversations
+    where id in (
+        select conversation_id
+        from comparison_forks
+        where comparison_id = ?
+    );
+    ",
+    )
+    .bind(comparison_id)
+    .execute(&mut *txn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    sqlx::query("delete from comparisons where id = ?;")
+        .bind(comparison_id)
+        .execute(&mut *txn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    txn.commit().await.map_err(|e| e.to_string())?;
+
+    // once the transaction is done, because stopping a reply stores what it wrote
+    for (message_id,) in still_generating {
+        stop_generation(&generations, message_id).await;
+    }
+
+    let path = format!("/conversations/{conversation_id}");
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "HX-Redirect",
+        HeaderValue::try_from(path).map_err(|e| e.to_string())?,
+    );
+
+    Ok(headers)
+}
+
+async fn comparisons_keep(
+    State(state): State<Arc<AppState>>,
+    Path(comparison_id): Path<i64>,
+) -> axum::response::Result<HeaderMap> {
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    // the forks stop being part of a comparison, and show up like any other conversation
+    sqlx::query("delete from comparisons where id = ?;")
+        .bind(comparison_id)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Redirect", HeaderValue::from_static("/conversations/"));
+
+    Ok(headers)
+}
+
+#[derive(Deserialize)]
+struct ConversationNameChangeForm {
+    conversation_name: String,
+}
+
+async fn conversations_edit_get(
+    Path(conversation_id): Path<i64>,
+) -> axum::response::Result<Markup> {
+    Ok(html! {
+        div
+            id="conversation-name-edit"
+            class="level-item"
+        {
+            form
+                hx-put=(format!("/conversations/{conversation_id}/edit"))
+                hx-target="#conversation-name-block"
+                hx-swap="outerHTML"
+            {
+                div class="field is-horizontal" {
+                    div class="field-body" {
+                        div class="field" {
+                            div class="control" {
+                                input type="text" name="conversation_name" class="input" placeholder="Conversation name" required;
+                            }
+                        }
+                        div class="field" {
+                            p class="control" {
+                                button class="button is-link" {
+                                    "Submit"
+                                }
+                            }
+                        }
+                        div class="field" {
+                            p class="control" {
+                                button
+                                    hx-get=(format!("/conversations/{}/edit/cancel", conversation_id))
+                                    hx-target="#conversation-name-edit"
+                                    hx-swap="outerHTML"
+                                    class="button is-link"
+                                {
+                                    "Cancel"
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+async fn conversations_edit_save(
+    State(state): State<Arc<AppState>>,
+    Path(conversation_id): Path<i64>,
+    Form(name_change_form): Form<ConversationNameChangeForm>,
+) -> axum::response::Result<Markup> {
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    sqlx::query(
+        "
+    update conversations
+    set
+        name = ?,
+        name_edited = 1
+    where id = ?",
+    )
+    .bind(&name_change_form.conversation_name)
+    .bind(conversation_id)
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    Ok(html! {
+        div
+            id="conversation-name-block"
+            class="level-left"
+        {
+            div class="level-item" {
+                h1 class="title" {
+                    (name_change_form.conversation_name)
+                }
+            }
+            div class="level-item" {
+                a hx-get=(format!("/conversations/{}/edit", conversation_id)) {
+                    "Edit"
+                }
+            }
+        }
+    })
+}
+
+async fn conversations_edit_cancel(
+    State(_state): State<Arc<AppState>>,
+    Path(conversation_id): Path<i64>,
+) -> axum::response::Result<Markup> {
+    Ok(html! {
+        div
+            id="conversation-name-edit"
+            class="level-item"
+        {
+            a
+                hx-get=(format!("/conversations/{}/edit", conversation_id))
+                hx-swap="outerHTML"
+                hx-target="#conversation-name-edit"
+            {
+                "Edit"
+            }
+        }
+    })
+}
+
+async fn conversations_delete(
+    State(state): State<Arc<AppState>>,
+    Path(conversation_id): Path<i64>,
+) -> axum::response::Result<HeaderMap> {
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    sqlx::query(
+        "
+    delete from conversations
+    where id = ?;",
+    )
+    .bind(conversation_id)
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let path = "/conversations/";
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "HX-Redirect",
+        HeaderValue::try_from(path).map_err(|e| e.to_string())?,
+    );
+
+    Ok(headers)
+}
+
+#[derive(Deserialize)]
+struct SystemPromptForm {
+    system_prompt: String,
+}
+
+async fn conversations_system_prompt_save(
+    State(state): State<Arc<AppState>>,
+    Path(conversation_id): Path<i64>,
+    Form(system_prompt_form): Form<SystemPromptForm>,
+) -> axum::response::Result<()> {
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    sqlx::query(
+        "
+    update conversations
+    set system_prompt = ?
+    where id = ?",
+    )
+    .bind(system_prompt_form.system_prompt)
+    .bind(conversation_id)
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct KeepAliveForm {
+    keep_alive: String,
+}
+
+async fn conversations_keep_alive_save(
+    State(state): State<Arc<AppState>>,
+    Path(conversation_id): Path<i64>,
+    Form(keep_alive_form): Form<KeepAliveForm>,
+) -> axum::response::Result<Markup> {
+    let keep_alive = keep_alive_form.keep_alive.trim();
+
+    if !keep_alive.is_empty() && !is_valid_keep_alive(keep_alive) {
+        return Ok(html! {
+            span class="has-text-danger" {
+                (keep_alive) " is not a duration Ollama understands. Try something like 30s, 10m or 1h30m."
+            }
+        });
+    }
+
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    sqlx::query(
+        "
+    update conversations
+    set keep_alive = ?
+    where id = ?",
+    )
+    .bind(if keep_alive.is_empty() {
+        None
+    } else {
+        Some(keep_alive)
+    })
+    .bind(conversation_id)
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    Ok(html! {
+        "Saved."
+    })
+}
+
+#[derive(Deserialize)]
+struct ResponseFormatForm {
+    response_format: ResponseFormat,
+    json_schema: String,
+}
+
+async fn conversations_response_format_save(
+    State(state): State<Arc<AppState>>,
+    Path(conversation_id): Path<i64>,
+    Form(response_format_form): Form<ResponseFormatForm>,
+) -> axum::response::Result<Markup> {
+    let json_schema = response_format_form.json_schema.trim();
+
+    if response_format_form.response_format == ResponseFormat::Schema {
+        let schema_error = match serde_json::from_str::<serde_json::Value>(json_schema) {
+            Ok(schema) => jsonschema::validator_for(&schema)
+                .err()
+                .map(|e| e.to_string()),
+            Err(e) => Some(e.to_string()),
+        };
+
+        if let Some(schema_error) = schema_error {
+            return Ok(html! {
+                span class="has-text-danger" {
+                    "That is not a valid JSON schema: " (schema_error)
+                }
+            });
+        }
+    }
+
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    sqlx::query(
+        "
+    update conversations
+    set
+        response_format = ?,
+        json_schema = ?
+    where id = ?",
+    )
+    .bind(response_format_form.response_format)
+    .bind(if json_schema.is_empty() {
+        None
+    } else {
+        Some(json_schema)
+    })
+    .bind(conversation_id)
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    Ok(html! {
+        "Saved."
+    })
+}
+
+#[derive(Deserialize)]
+struct ContextStrategyForm {
+    context_strategy: ContextStrategy,
+}
+
+async fn conversations_context_strategy_save(
+    State(state): State<Arc<AppState>>,
+    Path(conversation_id): Path<i64>,
+    Form(context_strategy_form): Form<ContextStrategyForm>,
+) -> axum::response::Result<()> {
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    sqlx::query(
+        "
+    update conversations
+    set context_strategy = ?
+    where id = ?",
+    )
+    .bind(context_strategy_form.context_strategy)
+    .bind(conversation_id)
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct ToolsForm {
+    // checkboxes are only sent when checked
+    tools_enabled: Option<String>,
+}
+
+async fn conversations_tools_save(
+    State(state): State<Arc<AppState>>,
+    Path(conversation_id): Path<i64>,
+    Form(tools_form): Form<ToolsForm>,
+) -> axum::response::Result<()> {
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    sqlx::query(
+        "
+    update conversations
+    set tools_enabled = ?
+    where id = ?",
+    )
+    .bind(tools_form.tools_enabled.is_some())
+    .bind(conversation_id)
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+async fn conversations_options_save(
+    State(state): State<Arc<AppState>>,
+    Path(conversation_id): Path<i64>,
+    Form(options): Form<GenerationOptions>,
+) -> axum::response::Result<()> {
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    sqlx::query(
+        "
+    update conversations
+    set
+        temperature = ?,
+        top_p = ?,
+        top_k = ?,
+        num_ctx = ?,
+        seed = ?,
+        repeat_penalty = ?,
+        stop = ?,
+        num_predict = ?
+    where id = ?",
+    )
+    .bind(options.temperature)
+    .bind(options.top_p)
+    .bind(options.top_k)
+    .bind(options.num_ctx)
+    .bind(options.seed)
+    .bind(options.repeat_penalty)
+    .bind(options.stop)
+    .bind(options.num_predict)
+    .bind(conversation_id)
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct ModelSelection {
+    #[serde(rename(deserialize = "model-id"))]
+    model_id: i64,
+}
+
+async fn select_model(
+    State(state): State<Arc<AppState>>,
+    Path(conversation_id): Path<i64>,
+    Form(model_selection): Form<ModelSelection>,
+) -> axum::response::Result<()> {
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    sqlx::query(
+        "
+    update conversations
+    set model_id = ?
+    where id = ?
+    ",
+    )
+    .bind(model_selection.model_id)
+    .bind(conversation_id)
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+async fn models_index(State(state): State<Arc<AppState>>) -> axum::response::Result<Markup> {
+    let unreachable_backends = state.unreachable_backends.lock().await.clone();
+
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    let backends = get_configured_backends(&mut conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // only Ollama can pull, unload and delete models
+    let ollama_backends: Vec<&Backend> = backends
+        .iter()
+        .filter(|backend| backend.kind == BackendKind::Ollama)
+        .collect();
+
+    let models = get_available_models(&mut conn, None)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(layout! {
+        html! {
+            div class="container mb-5" {
+                section class="section" {
+                    (backend_banner(&unreachable_backends))
+                    a href="/conversations/" {
+                        "Back"
+                    }
+                    h1 class="title" {
+                        "models"
+                    }
+
+                    @if !ollama_backends.is_empty() {
+                        form
+                            hx-post="/models/pull"
+                            hx-target="#model-pulls"
+                            hx-swap="afterbegin"
+                            hx-on::after-request="if(event.detail.successful) this.reset()"
+                        {
+                            div class="field has-addons" {
+                                @if ollama_backends.len() > 1 {
+                                    div class="control" {
+                                        div class="select" {
+                                            select name="backend_id" {
+                                                @for backend in ollama_backends.iter() {
+                                                    option value=(backend.id) {
+                                                        (backend.name)
+                                                    }
+                                                }
+                                            }
+                                        }
+                                    }
+                                } @else {
+                                    @for backend in ollama_backends.iter() {
+                                        input type="hidden" name="backend_id" value=(backend.id);
+                                    }
+                                }
+                                div class="control" {
+                                    input
+                                        class="input"
+                                        type="text"
+                                        name="name"
+                                        placeholder="a model to pull, like llama3.2"
+                                        required;
+                                }
+                                div class="control" {
+                                    button class="button is-link" {
+                                        "Pull"
+                                    }
+                                }
+                            }
+                        }
+
+                        div id="model-pulls" {}
+
+                        h2 class="subtitle mt-5" {
+                            "Loaded"
+                        }
+                        div id="loaded-models" hx-get="/models/loaded" hx-trigger="load, every 10s" {}
+                    }
+
+                    @for backend in backends.iter() {
+                        h2 class="subtitle mt-5" {
+                            (backend.name) " "
+                            span class="is-size-7" {
+                                (backend.url)
+                            }
+                        }
+                        table class="table" {
+                            tbody {
+                                @for model in models.iter().filter(|model| model.backend_id == backend.id) {
+                                    tr {
+                                        td {
+                                            a href=(format!("/models/{}", model.id)) {
+                                                (model.name)
+                                            }
+                                        }
+                                        td {
+                                            (model.size.map(format_bytes).unwrap_or_default())
+                                        }
+                                        td {
+                                            @if backend.kind == BackendKind::Ollama {
+                                                a
+                                                    hx-delete=(format!("/models/{}", model.id))
+                                                    hx-confirm=(format!("Really delete {} from {}?", model.name, backend.name))
+                                                    hx-target="closest tr"
+                                                    hx-swap="outerHTML"
+                                                {
+                                                    "Delete"
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+fn format_bytes(bytes: i64) -> String {
+    const GB: f64 = 1_000_000_000.0;
+    const MB: f64 = 1_000_000.0;
+
+    let bytes = bytes as f64;
+
+    if bytes >= GB {
+        format!("{:.1} GB", bytes / GB)
+    } else {
+        format!("{:.0} MB", bytes / MB)
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct OllamaShowResponse {
+    #[serde(default)]
+    parameters: String,
+    #[serde(default)]
+    template: String,
+    #[serde(default)]
+    details: OllamaModelDetails,
+    #[serde(default)]
+    capabilities: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct OllamaModelDetails {
+    format: Option<String>,
+    family: Option<String>,
+    parameter_size: Option<String>,
+    quantization_level: Option<String>,
+}
+
+async fn models_show(
+    State(state): State<Arc<AppState>>,
+    Path(model_id): Path<i64>,
+) -> axum::response::Result<Markup> {
+    let http_client = state.http_client.clone();
+
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    let model = get_model(&mut conn, model_id)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let details = async {
+        if model.backend_kind != BackendKind::Ollama {
+            anyhow::bail!("only Ollama backends have details about their models");
+        }
+
+        let resp = http_client
+            .post(format!("{}/api/show", model.backend_url))
+            .json(&serde_json::json!({ "model": model.name }))
+            .send()
+            .await?;
+
+        if !resp.status().is_success() {
+            let error = match resp.json::<OllamaErrorResponse>().await {
+                Ok(error_response) => error_response.error,
+                Err(e) => e.to_string(),
+            };
+
+            anyhow::bail!("Ollama returned an error: {error}");
+        }
+
+        anyhow::Ok(resp.json::<OllamaShowResponse>().await?)
+    }
+    .await;
+
+    Ok(layout! {
+        html! {
+            div class="container mb-5" {
+                section class="section" {
+                    a href="/models" {
+                        "Back"
+                    }
+                    h1 class="title" {
+                        (model.name)
+                    }
+                    h2 class="subtitle" {
+                        "on " (model.backend_name)
+                        @if let Some(size) = model.size {
+                            ", " (format_bytes(size))
+                        }
+                    }
+
+                    @match details {
+                        Ok(details) => {
+                            table class="table" {
+                                tbody {
+                                    @for (label, value) in [
+                                        ("Family", &details.details.family),
+                                        ("Parameters", &details.details.parameter_size),
+                                        ("Quantization", &details.details.quantization_level),
+                                        ("Format", &details.details.format),
+                                    ] {
+                                        tr {
+                                            th {
+                                                (label)
+                                            }
+                                            td {
+                                                (value.clone().unwrap_or_default())
+                                            }
+                                        }
+                                    }
+                                    tr {
+                                        th {
+                                            "Capabilities"
+                                        }
+                                        td {
+                                            (details.capabilities.join(", "))
+                                        }
+                                    }
+                                }
+                            }
+                            h3 class="subtitle" {
+                                "Parameters"
+                            }
+                            pre {
+                                (details.parameters)
+                            }
+                            h3 class="subtitle mt-4" {
+                                "Template"
+                            }
+                            pre {
+                                (details.template)
+                            }
+                        }
+                        Err(e) => {
+                            div class="notification is-warning" {
+                                "Could not get this model's details: " (e)
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+async fn get_model(conn: &mut sqlx::SqliteConnection, model_id: i64) -> sqlx::Result<Model> {
+    sqlx::query_as(
+        "
+        select
+            models.id,
+            models.name,
+            models.available,
+            models.size,
+            models.backend_id,
+            backends.name as backend_name,
+            backends.url as backend_url,
+            backends.kind as backend_kind
+        from models
+        inner join backends
+            on backends.id = models.backend_id
+        where models.id = ?
+        limit 1;
+        ",
+    )
+    .bind(model_id)
+    .fetch_one(conn)
+    .await
+}
+
+async fn get_conversation_model(
+    conn: &mut sqlx::SqliteConnection,
+    conversation_id: i64,
+) -> sqlx::Result<Model> {
+    let model_id: i64 =
+        sqlx::query_scalar("select model_id from conversations where id = ? limit 1;")
+            .bind(conversation_id)
+            .fetch_one(&mut *conn)
+            .await?;
+
+    get_model(conn, model_id).await
+}
+
+async fn get_available_models(
+    conn: &mut sqlx::SqliteConnection,
+    model_id: Option<i64>,
+) -> sqlx::Result<Vec<Model>> {
+    let model_ids: Vec<i64> = sqlx::query_scalar(
+        "
+        select models.id
+        from models
+        inner join backends
+            on backends.id = models.backend_id
+        where models.available = 1
+        or models.id = ?
+        order by models.name, backends.name;
+        ",
+    )
+    .bind(model_id)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let mut models = vec![];
+
+    for model_id in model_ids {
+        models.push(get_model(conn, model_id).await?);
+    }
+
+    Ok(models)
+}
+
+async fn models_delete(
+    State(state): State<Arc<AppState>>,
+    Path(model_id): Path<i64>,
+) -> axum::response::Result<Markup> {
+    let http_client = state.http_client.clone();
+
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    let model = get_model(&mut conn, model_id)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let result = async {
+        if model.backend_kind != BackendKind::Ollama {
+            anyhow::bail!("only Ollama backends can delete models");
+        }
+
+        let resp = http_client
+            .delete(format!("{}/api/delete", model.backend_url))
+            .json(&serde_json::json!({ "model": model.name }))
+            .send()
+            .await?;
+
+        let status = resp.status();
+
+        // it's already gone
+        if status.is_success() || status == StatusCode::NOT_FOUND {
+            anyhow::Ok(())
+        } else {
+            let error = match resp.json::<OllamaErrorResponse>().await {
+                Ok(error_response) => error_response.error,
+                Err(_) => status.to_string(),
+            };
+
+            anyhow::bail!("Ollama returned an error: {error}")
+        }
+    }
+    .await;
+
+    if let Err(e) = result {
+        return Ok(html! {
+            tr {
+                td colspan="3" class="has-text-danger" {
+                    "Could not delete " (model.name) ": " (e)
+                }
+            }
+        });
+    }
+
+    sqlx::query(
+        "
+    update models
+    set
+        available = 0,
+        updated_at = STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')
+    where id = ?
+    ",
+    )
+    .bind(model_id)
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    Ok(html! {})
+}
+
+#[derive(Deserialize, Debug)]
+struct OllamaPsResponse {
+    models: Vec<OllamaLoadedModel>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OllamaLoadedModel {
+    name: String,
+    #[serde(default)]
+    size: i64,
+    #[serde(default)]
+    size_vram: i64,
+    expires_at: Option<String>,
+}
+
+async fn models_loaded(State(state): State<Arc<AppState>>) -> axum::response::Result<Markup> {
+    let http_client = state.http_client.clone();
+
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    let backends = get_configured_backends(&mut conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut loaded = vec![];
+
+    for backend in backends
+        .into_iter()
+        .filter(|backend| backend.kind == BackendKind::Ollama)
+    {
+        let models = get_loaded_models(&http_client, &backend.url).await;
+        loaded.push((backend, models));
+    }
+
+    Ok(html! {
+        table class="table" {
+            thead {
+                tr {
+                    th { "Backend" }
+                    th { "Model" }
+                    th { "Size" }
+                    th { "In VRAM" }
+                    th { "Unloads at" }
+                    th { "" }
+                }
+            }
+            tbody {
+                @for (backend, models) in loaded.iter() {
+                    @match models {
+                        Ok(models) => {
+                            @for model in models {
+                                tr {
+                                    td { (backend.name) }
+                                    td { (model.name) }
+                                    td { (format_bytes(model.size)) }
+                                    td {
+                                        (format_bytes(model.size_vram))
+                                        @if model.size > 0 {
+                                            " (" (model.size_vram * 100 / model.size) "%)"
+                                        }
+                                    }
+                                    td { (model.expires_at.clone().unwrap_or_default()) }
+                                    td {
+                                        a
+                                            hx-post="/models/unload"
+                                            hx-vals=(serde_json::json!({ "backend_id": backend.id, "name": model.name }))
+                                            hx-target="#loaded-models"
+                                        {
+                                            "Unload"
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            tr {
+                                td { (backend.name) }
+                                td colspan="5" class="has-text-danger" {
+                                    (e)
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+async fn get_loaded_models(
+    http_client: &reqwest::Client,
+    ollama_url: &str,
+) -> anyhow::Result<Vec<OllamaLoadedModel>> {
+    let resp: OllamaPsResponse = http_client
+        .get(format!("{ollama_url}/api/ps"))
+        .timeout(std::time::Duration::from_secs(5))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(resp.models)
+}
+
+#[derive(Deserialize)]
+struct ModelUnloadForm {
+    backend_id: i64,
+    name: String,
+}
+
+async fn models_unload(
+    State(state): State<Arc<AppState>>,
+    Form(model_unload_form): Form<ModelUnloadForm>,
+) -> axum::response::Result<Markup> {
+    let http_client = state.http_client.clone();
+
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    let backend = get_backend(&mut conn, model_unload_form.backend_id)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    drop(conn);
+
+    let result = async {
+        if backend.kind != BackendKind::Ollama {
+            anyhow::bail!("only Ollama backends can unload models");
+        }
+
+        let resp = http_client
+            .post(format!("{}/api/generate", backend.url))
+            .json(&serde_json::json!({ "model": model_unload_form.name, "keep_alive": 0 }))
+            .send()
+            .await?;
+
+        if !resp.status().is_success() {
+            let error = match resp.json::<OllamaErrorResponse>().await {
+                Ok(error_response) => error_response.error,
+                Err(e) => e.to_string(),
+            };
+
+            anyhow::bail!("Ollama returned an error: {error}");
+        }
+
+        anyhow::Ok(())
+    }
+    .await;
+
+    let loaded = models_loaded(State(state)).await?;
+
+    Ok(html! {
+        @if let Err(e) = result {
+            p class="has-text-danger" {
+                "Could not unload " (model_unload_form.name) ": " (e)
+            }
+        }
+        (loaded)
+    })
+}
+
+#[derive(Deserialize)]
+struct ModelPullForm {
+    backend_id: i64,
+    name: String,
+}
+
+async fn models_pull_create(
+    State(state): State<Arc<AppState>>,
+    Form(model_pull_form): Form<ModelPullForm>,
+) -> axum::response::Result<Markup> {
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+
+    let backend = get_backend(&mut conn, model_pull_form.backend_id)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    drop(conn);
+
+    if backend.kind != BackendKind::Ollama {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "only Ollama backends can pull models",
+        )
+            .into());
+    }
+
+    let name = model_pull_form.name.trim().to_string();
+
+    let (pull_tx, pull_rx) = watch::channel(PullEvent::Progress(OllamaPullResponse {
+        status: "starting".to_string(),
+        total: None,
+        completed: None,
+    }));
+
+    let pull_tx = Arc::new(pull_tx);
+
+    let pull_id = state
+        .pulls
+        .next_id
+        .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+    state.pulls.by_id.lock().await.insert(
+        pull_id,
+        Pull {
+            tx: pull_tx.clone(),
+            unwatched: Some(pull_rx),
+        },
+    );
+
+    let pulls = state.pulls.clone();
+    let pool = state.pool.clone();
+    let http_client = state.http_client.clone();
+    let chat_backends = state.chat_backends.clone();
+    let pull_backend = backend.clone();
+    let pull_name = name.clone();
+
+    // the pull keeps going even if nobody is watching it
+    tokio::spawn(async move {
+        let event = match pull_model(
+            &pool,
+            http_client,
+            &chat_backends,
+            &pull_backend,
+            &pull_name,
+            &pull_tx,
+        )
+        .await
+        {
+            Ok(()) => PullEvent::Done,
+            Err(e) => {
+                error!("error pulling {}: {:?}", pull_name, e);
+                PullEvent::Error(e.to_string())
+            }
+        };
+
+        pull_tx.send_replace(event);
+
+        // kept until whoever is watching has seen how it ended
+        pull_tx.closed().await;
+        pulls.by_id.lock().await.remove(&pull_id);
+    });
+
+    Ok(html! {
+        div class="box" {
+            p {
+                "Pulling " strong { (name) } " to " (backend.name)
+            }
+            div class="model-pull-status" {
+                progress class="progress is-small" {}
+            }
+            div
+                hx-ext="sse"
+                sse-connect=(format!("/models/pull/{pull_id}/sse"))
+                sse-swap="PullProgress,PullDone,PullError"
+                hx-target="previous .model-pull-status"
+                hx-swap="innerHTML"
+            {
+                // stop listening once the pull is over
+                div
+                hx-get="/empty"
+                hx-trigger="sse:PullDone, sse:PullError"
+                hx-target="closest [sse-connect]"
+                hx-swap="delete" {}
+            }
+        }
+    })
+}
+
+#[derive(Clone, Deserialize, Debug)]
+struct OllamaPullResponse {
+    status: String,
+    total: Option<i64>,
+    completed: Option<i64>,
+}
+
+#[derive(Clone, Debug)]
+enum PullEvent {
+    Progress(OllamaPullResponse),
+    Done,
+    Error(String),
+}
+
+#[derive(Clone, Debug, Default)]
+struct Pulls {
+    next_id: Arc<std::sync::atomic::AtomicI64>,
+    by_id: Arc<Mutex<HashMap<i64, Pull>>>,
+}
+
+#[derive(Debug)]
+struct Pull {
+    tx: Arc<watch::Sender<PullEvent>>,
+    /// the pull's first receiver, kept for its first watcher,
+    /// so the pull isn't forgotten before anyone has watched it
+    unwatched: Option<watch::Receiver<PullEvent>>,
+}
+
+async fn models_pull_sse(
+    State(state): State<Arc<AppState>>,
+    Path(pull_id): Path<i64>,
+) -> axum::response::Result<Sse<impl Stream<Item = Result<Event, Infallible>>>> {
+    let pull_rx = {
+        let mut pulls = state.pulls.by_id.lock().await;
+
+        let pull = pulls
+            .get_mut(&pull_id)
+            .ok_or((StatusCode::NOT_FOUND, "no such pull"))?;
+
+        pull.unwatched.take().unwrap_or_else(|| pull.tx.subscribe())
+    };
+
+    let sse_stream = tokio_stream::wrappers::WatchStream::new(pull_rx)
+        .map(|pull_event| match pull_event {
+            PullEvent::Progress(progress) => Event::default()
+                .event("PullProgress")
+                .data(pull_progress(&progress).into_string()),
+            PullEvent::Done => Event::default().event("PullDone").data(
+                html! {
+                    p class="has-text-success" {
+                        "Done. "
+                        a href="/models" {
+                            "Reload"
+                        }
+                        " to see it."
+                    }
+                }
+                .into_string(),
+            ),
+            PullEvent::Error(error) => Event::default().event("PullError").data(
+                html! {
+                    p class="has-text-danger" {
+                        (error)
+                    }
+                }
+                .into_string()
+                .replace('\r', ""),
+            ),
+        })
+        .map(Ok);
+
+    Ok(Sse::new(sse_stream).keep_alive(
+        axum::response::sse::KeepAlive::new()
+            .interval(std::time::Duration::from_secs(1))
+            .text("keep-alive-text"),
+    ))
+}
+
+fn pull_progress(progress: &OllamaPullResponse) -> Markup {
+    html! {
+        p class="is-size-7" {
+            (progress.status)
+        }
+        @match (progress.completed, progress.total) {
+            (Some(completed), Some(total)) => {
+                progress class="progress is-small is-link" value=(completed) max=(total) {}
+            }
+            _ => {
+                progress class="progress is-small is-link" {}
+            }
+        }
+    }
+}
+
+async fn pull_model(
+    pool: &sqlx::Pool<Sqlite>,
+    http_client: reqwest::Client,
+    chat_backends: &ChatBackends,
+    backend: &Backend,
+    name: &str,
+    pull_tx: &watch::Sender<PullEvent>,
+) -> anyhow::Result<()> {
+    if backend.kind != BackendKind::Ollama {
+        anyhow::bail!("only Ollama backends can pull models");
+    }
+
+    let resp = http_client
+        .post(format!("{}/api/pull", backend.url))
+        .json(&serde_json::json!({ "model": name }))
+        .send()
+        .await
+        .map_err(|e| anyhow::anyhow!("could not reach Ollama: {e}"))?;
+
+    let mut lines = ndjson_lines(resp).await?;
+
+    while let Some(line) = lines.next().await {
+        let line = line.map_err(|e| anyhow::anyhow!("error receiving ndjson stream: {e}"))?;
+
+        match serde_json::from_str::<OllamaPullResponse>(&line) {
+            Ok(progress) => {
+                pull_tx.send_replace(PullEvent::Progress(progress));
+            }
+            Err(e) => {
+                if let Ok(error_response) = serde_json::from_str::<OllamaErrorResponse>(&line) {
+                    anyhow::bail!("Ollama returned an error: {}", error_response.error);
+                }
+
+                anyhow::bail!("could not understand Ollama's response ({e}): {line}");
+            }
+        }
+    }
+
+    match chat_backends.get(&backend.id) {
+        Some(chat_backend) => sync_backend_models(pool, chat_backend.as_ref(), backend.id).await,
+        None => Ok(()),
+    }
+}
+
+async fn get_configured_backends(conn: &mut sqlx::SqliteConnection) -> sqlx::Result<Vec<Backend>> {
+    sqlx::query_as(
+        "
+        select
+            id,
+            name,
+            url,
+            kind
+        from backends
+        where configured = 1
+        order by id;
+        ",
+    )
+    .fetch_all(conn)
+    .await
+}
+
+async fn get_backend(conn: &mut sqlx::SqliteConnection, backend_id: i64) -> sqlx::Result<Backend> {
+    sqlx::query_as(
+        "
+        select
+            id,
+            name,
+            url,
+            kind
+        from backends
+        where id = ?
+        limit 1;
+        ",
+    )
+    .bind(backend_id)
+    .fetch_one(conn)
+    .await
+}
+
+#[derive(Clone, Debug)]
+struct Tools {
+    pool: sqlx::Pool<Sqlite>,
+    history_pool: sqlx::Pool<Sqlite>,
+    files_dir: Option<PathBuf>,
+}
+
+const MAX_TOOL_FILE_BYTES: u64 = 256 * 1024;
+
+const MAX_TOOL_QUERY_ROWS: usize = 100;
+
+const TOOL_QUERY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+fn single_select(sql: &str) -> anyhow::Result<&str> {
+    let sql = sql.trim().trim_end_matches(';').trim_end();
+
+    if sql.contains(';') {
+        anyhow::bail!("only a single statement can be run");
+    }
+
+    let first_word = sql
+        .split(|c: char| !c.is_ascii_alphabetic())
+        .next()
+        .unwrap_or_default();
+
+    if !first_word.eq_ignore_ascii_case("select") && !first_word.eq_ignore_ascii_case("with") {
+        anyhow::bail!("only SELECT statements can be run");
+    }
+
+    Ok(sql)
+}
+
+impl Tools {
+    fn definitions(&self) -> Vec<serde_json::Value> {
+        let mut definitions = vec![
+            serde_json::json!({
+                "type": "function",
+                "function": {
+                    "name": "calculator",
+                    "description": "Evaluate an arithmetic expression, like `(2 + 3) * 4.5 / 7` or `sqrt(2) ^ 3`.",
+                    "parameters": {
+                        "type": "object",
+                        "properties": {
+                            "expression": {
+                                "type": "string",
+                                "description": "the expression to evaluate"
+                            }
+                        },
+                        "required": ["expression"]
+                    }
+                }
+            }),
+            serde_json::json!({
+                "type": "function",
+                "function": {
+                    "name": "current_datetime",
+                    "description": "Get the current date and time, in UTC and in local time.",
+                    "parameters": {
+                        "type": "object",
+                        "properties": {}
+                    }
+                }
+            }),
+            serde_json::json!({
+                "type": "function",
+                "function": {
+                    "name": "query_history",
+                    "description": "Run a read-only SQLite query over the user's chat history and get the rows back as JSON. \
+                        Tables: conversations (id, name, inserted_at), \
+                        messages (id, conversation_id, who, body, inserted_at, canonical). \
+                        `who` is 'Me' for the user and 'LlaMA' for the model. \
+                        Only messages with canonical = 1 are part of a conversation.",
+                    "parameters": {
+                        "type": "object",
+                        "properties": {
+                            "sql": {
+                                "type": "string",
+                                "description": "a single SELECT statement"
+                            }
+                        },
+                        "required": ["sql"]
+                    }
+                }
+            }),
+        ];
+
+        if self.files_dir.is_some() {
+            definitions.push(serde_json::json!({
+                "type": "function",
+                "function": {
+                    "name": "read_file",
+                    "description": "Read a text file from the user's shared directory.",
+                    "parameters": {
+                        "type": "object",
+                        "properties": {
+                            "path": {
+                                "type": "string",
+                                "description": "the file's path, relative to the shared directory"
+                            }
+                        },
+                        "required": ["path"]
+                    }
+                }
+            }));
+        }
+
+        definitions
+    }
+
+    async fn run(
+        &self,
+        ollama_response_message_id: i64,
+        tool_calls: &[ToolCall],
+    ) -> anyhow::Result<Vec<(usize, Message)>> {
+        let mut conn = self.pool.acquire().await?;
+
+        let mut txn = conn.begin().await?;
+
+        let ollama_response = get_message(&mut txn, ollama_response_message_id).await?;
+
+        let mut messages = vec![];
+
+        for tool_call in tool_calls {
+            let name = &tool_call.function.name;
+
+            // errors go to the model, which can often correct itself
+            let result = match self.call(tool_call).await {
+                Ok(result) => result,
+                Err(e) => format!("error: {e}"),
+            };
+
+            for (who, body) in [
+                (Who::ToolCall, tool_call.function.arguments.to_string()),
+                (Who::ToolResult, result),
+            ] {
+                let message: Message = sqlx::query_as(
+                    "
+                insert into messages (
+                    who,
+                    body,
+                    tool_name,
+                    conversation_id,
+                    reply_id,
+                    reply_first_version_id,
+                    inserted_at,
+                    canonical
+                ) values (?, ?, ?, ?, ?, ?, ?, (select canonical from messages where id = ?))
+                returning *;
+                ",
+                )
+                .bind(who)
+                .bind(body)
+                .bind(name)
+                .bind(ollama_response.conversation_id)
+                .bind(ollama_response.id)
+                .bind(ollama_response.first_version_id())
+                .bind(&ollama_response.inserted_at)
+                .bind(ollama_response.id)
+                .fetch_one(&mut *txn)
+                .await?;
+
+                messages.push(message);
+            }
+        }
+
+        let mut positioned_messages = vec![];
+
+        for message in messages {
+            let position = message_position(&mut txn, &message).await?;
+            positioned_messages.push((position, message));
+        }
+
+        txn.commit().await?;
+
+        Ok(positioned_messages)
+    }
+
+    async fn call(&self, tool_call: &ToolCall) -> anyhow::Result<String> {
+        let arguments = &tool_call.function.arguments;
+
+        let string_argument = |name: &str| {
+            arguments
+                .get(name)
+                .and_then(|argument| argument.as_str())
+                .ok_or_else(|| anyhow::anyhow!("missing the `{name}` argument"))
+        };
+
+        match tool_call.function.name.as_str() {
+            "calculator" => {
+                let expression = string_argument("expression")?;
+
+                let value = fasteval::ez_eval(expression, &mut fasteval::EmptyNamespace)
+                    .map_err(|e| anyhow::anyhow!("could not evaluate {expression}: {e:?}"))?;
+
+                Ok(value.to_string())
+            }
+            "current_datetime" => {
+                let (utc, local): (String, String) = sqlx::query_as(
+                    "select STRFTIME('%Y-%m-%d %H:%M:%S', 'NOW'), STRFTIME('%Y-%m-%d %H:%M:%S', 'NOW', 'localtime');",
+                )
+                .fetch_one(&self.pool)
+                .await?;
+
+                Ok(format!("{utc} UTC, {local} local time"))
+            }
+            "query_history" => {
+                let sql = string_argument("sql")?;
+
+                self.query_history(sql, TOOL_QUERY_TIMEOUT).await
+            }
+            "read_file" => {
+                let path = string_argument("path")?;
+
+                self.read_file(path).await
+            }
+            name => anyhow::bail!("there is no tool named {name}"),
+        }
+    }
+
+    async fn query_history(
+        &self,
+        sql: &str,
+        timeout: std::time::Duration,
+    ) -> anyhow::Result<String> {
+        use sqlx::{Column, Row, TypeInfo, ValueRef};
+
+        let sql = single_select(sql)?;
+
+        let mut conn = self.history_pool.acquire().await?;
+
+        // sqlite checks in on this every so often, and stops the query once it returns false.
+        // set on every query, so a query that was dropped partway can't stop the next one
+        let deadline = std::time::Instant::now() + timeout;
+        conn.lock_handle()
+            .await?
+            .set_progress_handler(1000, move || std::time::Instant::now() < deadline);
+
+        let rows: Result<Vec<_>, _> = sqlx::query(sql)
+            .fetch(&mut *conn)
+            .take(MAX_TOOL_QUERY_ROWS + 1)
+            .collect()
+            .await;
+
+        conn.lock_handle().await?.remove_progress_handler();
+
+        let rows = rows.map_err(|e| {
+            if std::time::Instant::now() >= deadline {
+                anyhow::anyhow!("the query took too long")
+            } else {
+                e.into()
+            }
+        })?;
+
+        let mut results = vec![];
+
+        for row in rows.iter().take(MAX_TOOL_QUERY_ROWS) {
+            let mut result = serde_json::Map::new();
+
+            for column in row.columns() {
+                let i = column.ordinal();
+
+                let raw_value = row.try_get_raw(i)?;
+
+                let value = if raw_value.is_null() {
+                    serde_json::Value::Null
+                } else {
+                    match raw_value.type_info().name() {
+                        "INTEGER" => row.try_get::<i64, _>(i)?.into(),
+                        "REAL" => row.try_get::<f64, _>(i)?.into(),
+                        "BLOB" => format!("<{} bytes>", row.try_get::<Vec<u8>, _>(i)?.len()).into(),
+                        _ => row.try_get::<String, _>(i)?.into(),
+                    }
+                };
+
+                result.insert(column.name().to_string(), value);
+            }
+
+            results.push(serde_json::Value::Object(result));
+        }
+
+        let mut output = serde_json::to_string(&results)?;
+
+        if rows.len() > MAX_TOOL_QUERY_ROWS {
+            output.push_str(&format!(
+                "\n(there are more rows than the {MAX_TOOL_QUERY_ROWS} shown)"
+            ));
+        }
+
+        Ok(output)
+    }
+
+    async fn read_file(&self, path: &str) -> anyhow::Result<String> {
+        let Some(files_dir) = &self.files_dir else {
+            anyhow::bail!("reading files is turned off");
+        };
+
+        let files_dir = tokio::fs::canonicalize(files_dir).await?;
+
+        let path = tokio::fs::canonicalize(files_dir.join(path))
+            .await
+            .map_err(|e| anyhow::anyhow!("could not open {path}: {e}"))?;
+
+        // no escaping the directory with `..` or symlinks
+        if !path.starts_with(&files_dir) {
+            anyhow::bail!("{} is outside of the shared directory", path.display());
+        }
+
+        let file = tokio::fs::File::open(&path).await?;
+
+        let mut contents = vec![];
+
+        tokio::io::AsyncReadExt::read_to_end(
+            &mut tokio::io::AsyncReadExt::take(file, MAX_TOOL_FILE_BYTES),
+            &mut contents,
+        )
+        .await?;
+
+        Ok(String::from_utf8_lossy(&contents).into_owned())
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Titles {
+    pool: sqlx::Pool<Sqlite>,
+    chat_backends: ChatBackends,
+    limits: Limits,
+    model: Option<String>,
+}
+
+const MAX_TITLE_CHARS: usize = 80;
+
+impl Titles {
+    async fn write(&self, reply_id: i64) -> anyhow::Result<()> {
+        let mut conn = self.pool.acquire().await?;
+
+        let (conversation_id,): (i64,) =
+            sqlx::query_as("select conversation_id from messages where id = ? limit 1;")
+                .bind(reply_id)
+                .fetch_one(&mut *conn)
+                .await?;
+
+        // the first exchange: the user's first message, and the first reply that finished
+        let mut first_exchange = vec![];
+
+        for who in [Who::Me, Who::Llama] {
+            let body: Option<(String,)> = sqlx::query_as(
+                "
+            select body
+            from messages
+            where conversation_id = ?
+            and canonical = 1
+            and who = ?
+            and status = ?
+            order by inserted_at, coalesce(version_of, id)
+            limit 1;
+            ",
+            )
+            .bind(conversation_id)
+            .bind(&who)
+            .bind(MessageStatus::Done)
+            .fetch_optional(&mut *conn)
+            .await?;
+
+            let Some((body,)) = body else {
+                return Ok(());
+            };
+
+            first_exchange.push((who, body));
+        }
+
+        // only ever tried once, so a title that fails or comes back empty
+        // isn't tried again after every reply
+        let attempted: Option<(i64,)> = sqlx::query_as(
+            "
+        update conversations
+        set title_attempted = 1
+        where id = ?
+        and title_attempted = 0
+        and name = ?
+        and name_edited = 0
+        returning id;
+        ",
+        )
+        .bind(conversation_id)
+        .bind(DEFAULT_CONVERSATION_NAME)
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        if attempted.is_none() {
+            return Ok(());
+        }
+
+        let model = match &self.model {
+            Some(model_name) => {
+                let model_id: Option<i64> = sqlx::query_scalar(
+                    "
+                select id
+                from models
+                where name = ?
+                and available = 1
+                order by backend_id
+                limit 1;
+                ",
+                )
+                .bind(model_name)
+                .fetch_optional(&mut *conn)
+                .await?;
+
+                let model_id = model_id.ok_or_else(|| {
+                    anyhow::anyhow!("no model named {model_name} to write titles with")
+                })?;
+
+                get_model(&mut conn, model_id).await?
+            }
+            None => get_conversation_model(&mut conn, conversation_id).await?,
+        };
+
+        let chat_backend = self.chat_backends.get(&model.backend_id).ok_or_else(|| {
+            anyhow::anyhow!("the {} backend isn't configured", model.backend_name)
+        })?;
+
+        let transcript: String = first_exchange
+            .iter()
+            .map(|(who, body)| {
+                let body: String = body.chars().take(2000).collect();
+                format!("{}: {body}\n\n", who.role())
+            })
+            .collect();
+
+        let request = ChatRequest {
+            model: model.name.clone(),
+            messages: vec![
+                ChatRequestMessage::text(
+                    "system",
+                    "Write a title of at most six words for a conversation that starts like this. \
+                    Reply with only the title."
+                        .to_string(),
+                ),
+                ChatRequestMessage::text("user", transcript),
+            ],
+            options: GenerationOptions::default(),
+            keep_alive: None,
+            tools: vec![],
+            format: None,
+        };
+
+        let permit = self.limits.acquire(model.backend_id).await?;
+
+        let title = chat_backend.complete(&request).await?;
+
+        drop(permit);
+
+        // models like to dress titles up
+        let title: String = title
+            .lines()
+            .map(|line| line.trim_matches(|c: char| c.is_whitespace() || "\"'*#`.".contains(c)))
+            .find(|line| !line.is_empty())
+            .unwrap_or_default()
+            .chars()
+            .take(MAX_TITLE_CHARS)
+            .collect();
+
+        if title.is_empty() {
+            return Ok(());
+        }
+
+        // unless the user renamed it while the title was being written
+        sqlx::query(
+            "
+        update conversations
+        set name = ?
+        where id = ?
+        and name = ?
+        and name_edited = 0;
+        ",
+        )
+        .bind(&title)
+        .bind(conversation_id)
+        .bind(DEFAULT_CONVERSATION_NAME)
+        .execute(&mut *conn)
+        .await?;
+
+        info!("titled conversation {conversation_id} {title:?}");
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct AppState {
+    pool: sqlx::Pool<Sqlite>,
+    http_client: reqwest::Client,
+    chat_backends: ChatBackends,
+    generations: Generations,
+    unreachable_backends: UnreachableBackends,
+    tools: Tools,
+    queue: GenerationQueue,
+    pulls: Pulls,
+}
+
+#[derive(Clone, Debug, PartialEq, sqlx::Type)]
+#[sqlx(rename_all = "lowercase")]
+enum MessageStatus {
+    Generating,
+    Done,
+    Failed,
+    Stopped,
+}
+
+#[derive(Clone, Debug, PartialEq, sqlx::Type, Deserialize, Serialize)]
+enum Who {
+    #[sqlx(rename = "Me")]
+    Me,
+    #[sqlx(rename = "LlaMA")]
+    Llama,
+    #[sqlx(rename = "ToolCall")]
+    ToolCall,
+    #[sqlx(rename = "ToolResult")]
+    ToolResult,
+}
+
+impl Who {
+    fn role(&self) -> &'static str {
+        match self {
+            Who::Me => "user",
+            Who::Llama | Who::ToolCall => "assistant",
+            Who::ToolResult => "tool",
+        }
+    }
+}
+
+impl Display for Who {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Who::Me => write!(f, "Me"),
+            Who::Llama => write!(f, "LlaMA"),
+            Who::ToolCall => write!(f, "Tool call"),
+            Who::ToolResult => write!(f, "Tool result"),
+        }
+    }
+}
+
+async fn add_column_if_missing(
+    conn: &mut sqlx::SqliteConnection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> anyhow::Result<()> {
+    let (exists,): (bool,) =
+        sqlx::query_as("select count(*) > 0 from pragma_table_info(?) where name = ?;")
+            .bind(table)
+            .bind(column)
+            .fetch_one(&mut *conn)
+            .await?;
+
+    if !exists {
+        sqlx::query(&format!(
+            "alter table {table} add column {column} {definition};"
+        ))
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+fn parse_concurrency(s: &str) -> Result<(String, usize), String> {
+    let (name, concurrency) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=N, got `{s}`"))?;
+
+    match concurrency.parse() {
+        Ok(concurrency) if !name.is_empty() && concurrency > 0 => {
+            Ok((name.to_string(), concurrency))
+        }
+        _ => Err(format!("expected NAME=N, with N at least 1, got `{s}`")),
+    }
+}
+
+fn parse_named_backend(s: &str) -> Result<(String, String), String> {
+    let (name, url) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=URL, got `{s}`"))?;
+
+    if name.is_empty() || url.is_empty() {
+        return Err(format!("expected NAME=URL, got `{s}`"));
+    }
+
+    Ok((name.to_string(), url.trim_end_matches('/').to_string()))
+}
+
+#[derive(Debug, Parser)]
+struct Config {
+    #[arg(long, env, default_value = "conversations.db")]
+    database: String,
+    #[arg(long, env, default_value = "3000")]
+    port: u16,
+    /// the Ollama server for the backend named "default"
+    #[arg(long, env, default_value = "http://localhost:11434")]
+    ollama_url: String,
+    /// additional Ollama servers, as NAME=URL. can be given more than once
+    #[arg(
+        long = "backend",
+        env = "BACKENDS",
+        value_delimiter = ',',
+        value_parser = parse_named_backend
+    )]
+    backends: Vec<(String, String)>,
+    /// servers with an OpenAI-compatible API, like llama.cpp's server, vLLM or LM Studio,
+    /// as NAME=URL, without the `/v1`. can be given more than once
+    #[arg(
+        long = "openai-backend",
+        env = "OPENAI_BACKENDS",
+        value_delimiter = ',',
+        value_parser = parse_named_backend
+    )]
+    openai_backends: Vec<(String, String)>,
+    /// a directory models may read files from with the `read_file` tool
+    #[arg(long, env)]
+    tools_dir: Option<PathBuf>,
+    /// a small model to title conversations with. without it, conversations title themselves
+    #[arg(long, env)]
+    title_model: Option<String>,
+    /// how many requests a backend is sent at once, counting replies, titles and summaries,
+    /// as NAME=N. the rest wait their turn. backends not given get 2. can be given more than once
+    #[arg(
+        long = "concurrency",
+        env = "CONCURRENCY",
+        value_delimiter = ',',
+        value_parser = parse_concurrency
+    )]
+    concurrency: Vec<(String, usize)>,
+}
+
+async fn migrate(conn: &mut sqlx::SqliteConnection) -> anyhow::Result<()> {
+    let mut txn = conn.begin().await?;
+
+    sqlx::query(
+        "create table if not exists models (
+            id integer primary key autoincrement not null,
+            name text not null,
+            inserted_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+            updated_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'))
+        );
+        ",
+    )
+    .execute(&mut *txn)
+    .await?;
+
+    sqlx::query(
+        "create table if not exists backends (
+            id integer primary key autoincrement not null,
+            name text not null,
+            url text not null,
+            inserted_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+            updated_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'))
+        );
+        ",
+    )
+    .execute(&mut *txn)
+    .await?;
+
+    sqlx::query("create unique index if not exists backends_name on backends (name);")
+        .execute(&mut *txn)
+        .await?;
+
+    add_column_if_missing(
+        &mut txn,
+        "models",
+        "backend_id",
+        "integer references backends(id)",
+    )
+    .await?;
+
+    // a model name is only unique per backend
+    sqlx::query("drop index if exists models_name;")
+        .execute(&mut *txn)
+        .await?;
+
+    sqlx::query(
+        "create unique index if not exists models_backend_id_name on models (backend_id, name);",
+    )
+    .execute(&mut *txn)
+    .await?;
+
+    sqlx::query(
+        "create table if not exists conversations (
+            id integer primary key autoincrement not null,
+            name text not null,
+            model_id integer not null default 1,
+            source_conversation_id integer,
+            inserted_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+            updated_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+
+            foreign key(model_id) references models(id)
+        );",
+    )
+    .execute(&mut *txn)
+    .await?;
+
+    sqlx::query(
+        "create table if not exists messages (
+            id integer primary key autoincrement not null,
+            body text not null,
+            who text not null,
+            conversation_id integer not null,
+            inserted_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+            updated_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+
+            foreign key(conversation_id) references conversations(id) on delete cascade
+        )",
+    )
+    .execute(&mut *txn)
+    .await?;
+
+    add_column_if_missing(
+        &mut txn,
+        "conversations",
+        "system_prompt",
+        "text not null default ''",
+    )
+    .await?;
+
+    sqlx::query(
+        "create table if not exists attachments (
+            id integer primary key autoincrement not null,
+            message_id integer not null,
+            content_type text not null,
+            data blob not null,
+            inserted_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+
+            foreign key(message_id) references messages(id) on delete cascade
+        )",
+    )
+    .execute(&mut *txn)
+    .await?;
+
+    sqlx::query("create index if not exists attachments_message_id on attachments (message_id)")
+        .execute(&mut *txn)
+        .await?;
+
+    add_column_if_missing(&mut txn, "conversations", "keep_alive", "text").await?;
+
+    add_column_if_missing(
+        &mut txn,
+        "conversations",
+        "tools_enabled",
+        "integer not null default 0",
+    )
+    .await?;
+
+    add_column_if_missing(&mut txn, "messages", "tool_name", "text").await?;
+
+    add_column_if_missing(
+        &mut txn,
+        "messages",
+        "reply_id",
+        "integer references messages(id) on delete cascade",
+    )
+    .await?;
+
+    add_column_if_missing(&mut txn, "messages", "reply_first_version_id", "integer").await?;
+
+    sqlx::query("create index if not exists messages_reply_id on messages (reply_id)")
+        .execute(&mut *txn)
+        .await?;
+
+    add_column_if_missing(
+        &mut txn,
+        "conversations",
+        "response_format",
+        "text not null default 'text'",
+    )
+    .await?;
+
+    add_column_if_missing(&mut txn, "conversations", "json_schema", "text").await?;
+
+    add_column_if_missing(&mut txn, "messages", "json_valid", "integer").await?;
+
+    add_column_if_missing(&mut txn, "messages", "json_error", "text").await?;
+
+    add_column_if_missing(
+        &mut txn,
+        "conversations",
+        "context_strategy",
+        "text not null default 'all'",
+    )
+    .await?;
+
+    add_column_if_missing(&mut txn, "messages", "pinned", "integer not null default 0").await?;
+
+    add_column_if_missing(
+        &mut txn,
+        "messages",
+        "context_cut_before",
+        "integer references messages(id) on delete set null",
+    )
+    .await?;
+
+    add_column_if_missing(&mut txn, "messages", "context_summary", "text").await?;
+
+    add_column_if_missing(&mut txn, "messages", "thinking", "text not null default ''").await?;
+
+    add_column_if_missing(
+        &mut txn,
+        "backends",
+        "kind",
+        "text not null default 'ollama'",
+    )
+    .await?;
+
+    add_column_if_missing(
+        &mut txn,
+        "backends",
+        "configured",
+        "integer not null default 1",
+    )
+    .await?;
+
+    add_column_if_missing(
+        &mut txn,
+        "conversations",
+        "name_edited",
+        "integer not null default 0",
+    )
+    .await?;
+
+    add_column_if_missing(
+        &mut txn,
+        "conversations",
+        "title_attempted",
+        "integer not null default 0",
+    )
+    .await?;
+
+    sqlx::query(
+        "create table if not exists comparisons (
+            id integer primary key autoincrement not null,
+            conversation_id integer not null,
+            prompt text not null,
+            inserted_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+
+            foreign key(conversation_id) references conversations(id) on delete cascade
+        )",
+    )
+    .execute(&mut *txn)
+    .await?;
+
+    // each model's answer is a fork of the conversation, starting at the prompt
+    sqlx::query(
+        "create table if not exists comparison_forks (
+            comparison_id integer not null,
+            conversation_id integer not null,
+            prompt_message_id integer not null,
+
+            primary key(comparison_id, conversation_id),
+            foreign key(comparison_id) references comparisons(id) on delete cascade,
+            foreign key(conversation_id) references conversations(id) on delete cascade,
+            foreign key(prompt_message_id) references messages(id) on delete cascade
+        )",
+    )
+    .execute(&mut *txn)
+    .await?;
+
+    sqlx::query(
+        "create index if not exists comparison_forks_conversation_id on comparison_forks (conversation_id)",
+    )
+    .execute(&mut *txn)
+    .await?;
+
+    // every time a reply was asked for, and what became of it
+    sqlx::query(
+        "create table if not exists generations (
+            id integer primary key autoincrement not null,
+            message_id integer not null,
+            backend_id integer not null,
+            status text not null default 'queued',
+            inserted_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+            started_at datetime,
+            finished_at datetime,
+
+            foreign key(message_id) references messages(id) on delete cascade,
+            foreign key(backend_id) references backends(id)
+        )",
+    )
+    .execute(&mut *txn)
+    .await?;
+
+    sqlx::query("create index if not exists generations_message_id on generations (message_id)")
+        .execute(&mut *txn)
+        .await?;
+
+    sqlx::query(
+        "create index if not exists generations_backend_id_status on generations (backend_id, status)",
+    )
+    .execute(&mut *txn)
+    .await?;
+
+    add_column_if_missing(
+        &mut txn,
+        "messages",
+        "status",
+        "text not null default 'done'",
+    )
+    .await?;
+
+    add_column_if_missing(&mut txn, "messages", "error", "text").await?;
+
+    add_column_if_missing(
+        &mut txn,
+        "messages",
+        "version_of",
+        "integer references messages(id) on delete cascade",
+    )
+    .await?;
+
+    // whether this is the version of a message that the conversation continues from
+    add_column_if_missing(
+        &mut txn,
+        "messages",
+        "canonical",
+        "integer not null default 1",
+    )
+    .await?;
+
+    // nothing is generating yet, so these were cut off by a restart
+    sqlx::query(
+        "
+    update messages
+    set
+        status = 'failed',
+        error = 'ochat stopped before this reply was finished'
+    where status = 'generating';
+    ",
+    )
+    .execute(&mut *txn)
+    .await?;
+
+    sqlx::query(
+        "
+    update generations
+    set status = ?
+    where status in (?, ?);
+    ",
+    )
+    .bind(GenerationStatus::Interrupted)
+    .bind(GenerationStatus::Queued)
+    .bind(GenerationStatus::Running)
+    .execute(&mut *txn)
+    .await?;
+
+    add_column_if_missing(
+        &mut txn,
+        "models",
+        "available",
+        "integer not null default 1",
+    )
+    .await?;
+
+    add_column_if_missing(&mut txn, "models", "size", "integer").await?;
+
+    for (column, definition) in [
+        ("model", "text"),
+        ("created_at", "text"),
+        ("total_duration", "integer"),
+        ("load_duration", "integer"),
+        ("prompt_eval_count", "integer"),
+        ("prompt_eval_duration", "integer"),
+        ("eval_count", "integer"),
+        ("eval_duration", "integer"),
+        ("done_reason", "text"),
+    ] {
+        add_column_if_missing(&mut txn, "messages", column, definition).await?;
+    }
+
+    for (column, definition) in [
+        ("temperature", "real"),
+        ("top_p", "real"),
+        ("top_k", "integer"),
+        ("num_ctx", "integer"),
+        ("seed", "integer"),
+        ("repeat_penalty", "real"),
+        ("stop", "text"),
+        ("num_predict", "integer"),
+    ] {
+        add_column_if_missing(&mut txn, "conversations", column, definition).await?;
+    }
+
+    txn.commit().await?;
+
+    Ok(())
+}
+
+fn app(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/", get(conversations_index))
+        .route("/conversations/", get(conversations_index))
+        .route("/conversations/{id}", get(conversations_show))
+        .route("/conversations/{id}/edit", get(conversations_edit_get))
+        .route("/conversations/{id}/edit", put(conversations_edit_save))
+        .route(
+            "/conversations/{id}/edit/cancel",
+            get(conversations_edit_cancel),
+        )
+        .route("/conversations/{id}/delete", delete(conversations_delete))
+        .route(
+            "/conversations/{id}/system-prompt",
+            put(conversations_system_prompt_save),
+        )
+        .route("/conversations/{id}/tools", put(conversations_tools_save))
+        .route(
+            "/conversations/{id}/context-strategy",
+            put(conversations_context_strategy_save),
+        )
+        .route(
+            "/conversations/{id}/response-format",
+            put(conversations_response_format_save),
+        )
+        .route(
+            "/conversations/{id}/keep-alive",
+            put(conversations_keep_alive_save),
+        )
+        .route(
+            "/conversations/{id}/options",
+            put(conversations_options_save),
+        )
+        .route(
+            "/conversations/{conversation_id}/fork/{message_id}",
+            post(conversations_fork_create),
+        )
+        .route(
+            "/conversations/{id}/compare",
+            post(conversations_compare_create),
+        )
+        .route("/conversations/new", post(conversations_create))
+        .route("/comparisons/{id}", get(comparisons_show))
+        .route(
+            "/comparisons/{id}/pick/{conversation_id}",
+            post(comparisons_pick),
+        )
+        .route("/comparisons/{id}/keep", post(comparisons_keep))
+        .route(
+            "/messages/new",
+            post(messages_create).layer(DefaultBodyLimit::max(MAX_MESSAGE_BYTES)),
+        )
+        .route("/attachments/{id}", get(attachments_show))
+        .route("/messages/{id}/sse", get(messages_sse))
+        .route("/messages/{id}/retry", post(messages_retry))
+        .route("/messages/{id}/queue", get(messages_queue))
+        .route("/generations/resume", post(generations_resume))
+        .route("/messages/{id}", get(messages_show))
+        .route("/messages/{id}/edit", get(messages_edit_get))
+        .route("/messages/{id}/edit", post(messages_edit_save))
+        .route("/messages/{id}/stop", post(messages_stop))
+        .route("/messages/{id}/regenerate", post(messages_regenerate))
+        .route("/messages/{id}/pin", post(messages_pin))
+        .route(
+            "/messages/{id}/versions/{direction}",
+            post(messages_version_select),
+        )
+        .route("/empty", get(|| async {}))
+        .route("/backends/status", get(backends_status))
+        .route("/models/select/{conversation_id}", put(select_model))
+        .route("/models", get(models_index))
+        .route("/models/pull", post(models_pull_create))
+        .route("/models/loaded", get(models_loaded))
+        .route("/models/unload", post(models_unload))
+        .route("/models/pull/{id}/sse", get(models_pull_sse))
+        .route("/models/{id}", get(models_show))
+        .route("/models/{id}", delete(models_delete))
+        .route(
+            "/dev/state",
+            get(|State(state): State<Arc<AppState>>| async move { format!("{:#?}", state) }),
+        )
+        .with_state(state)
+        .layer(tower_http::compression::CompressionLayer::new())
+        .layer(tower_http::trace::TraceLayer::new_for_http())
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .init();
+
+    let config = Config::parse();
+
+    let opts =
+        sqlx::sqlite::SqliteConnectOptions::from_str(&format!("sqlite://{}", config.database))?
+            .busy_timeout(std::time::Duration::from_secs(5))
+            .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal)
+            .create_if_missing(true)
+            .foreign_keys(true);
+
+    let pool = sqlx::SqlitePool::connect_with(opts.clone()).await?;
+
+    let history_pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(2)
+        .connect_with(opts.read_only(true))
+        .await?;
+
+    let mut connection = pool.acquire().await?;
+
+    migrate(&mut connection).await?;
+
+    let http_client = reqwest::Client::new();
+
+    let mut txn = connection.begin().await?;
+
+    let configured_backends = std::iter::once((
+        "default".to_string(),
+        config.ollama_url.clone(),
+        BackendKind::Ollama,
+    ))
+    .chain(
+        config
+            .backends
+            .iter()
+            .map(|(name, url)| (name.clone(), url.clone(), BackendKind::Ollama)),
+    )
+    .chain(
+        config
+            .openai_backends
+            .iter()
+            .map(|(name, url)| (name.clone(), url.clone(), BackendKind::OpenAi)),
+    );
+
+    // backends that aren't in the config anymore stay, for the conversations that used them
+    sqlx::query("update backends set configured = 0;")
+        .execute(&mut *txn)
+        .await?;
+
+    let mut backends = vec![];
+
+    for (name, url, kind) in configured_backends {
+        let backend: Backend = sqlx::query_as(
+            "
+        insert into backends
+        (name, url, kind) values (?, ?, ?)
+        on conflict (name) do update set
+            url = excluded.url,
+            kind = excluded.kind,
+            configured = 1,
+            updated_at = STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')
+        returning id, name, url, kind;
+        ",
+        )
+        .bind(name)
+        .bind(url.trim_end_matches('/'))
+        .bind(kind)
+        .fetch_one(&mut *txn)
+        .await?;
+
+        backends.push(backend);
+    }
+
+    // models cached before there were multiple backends
+    // all came from the default one
+    sqlx::query(
+        "
+    update models
+    set backend_id = (select id from backends where name = 'default')
+    where backend_id is null;
+    ",
+    )
+    .execute(&mut *txn)
+    .await?;
+
+    // but their models can't be used, so they're hidden like any other removed model
+    sqlx::query(
+        "
+    update models
+    set available = 0
+    where backend_id in (select id from backends where configured = 0);
+    ",
+    )
+    .execute(&mut *txn)
+    .await?;
+
+    txn.commit().await?;
+
+    let unreachable_backends = Arc::new(Mutex::new(BTreeMap::new()));
+
+    let chat_backends: ChatBackends = Arc::new(
+        backends
+            .iter()
+            .map(|backend| {
+                (
+                    backend.id,
+                    backend.kind.connect(http_client.clone(), &backend.url),
+                )
+            })
+            .collect(),
+    );
+
+    let concurrency: HashMap<String, usize> = config.concurrency.into_iter().collect();
+
+    let limits = Limits::new(backends.iter().map(|backend| {
+        let concurrency = concurrency
+            .get(&backend.name)
+            .copied()
+            .unwrap_or(DEFAULT_CONCURRENCY);
+
+        (backend.id, concurrency)
+    }));
+
+    spawn_backend_sync_task(
+        pool.clone(),
+        chat_backends.clone(),
+        backends,
+        unreachable_backends.clone(),
+    );
+
+    let tools = Tools {
+        pool: pool.clone(),
+        history_pool,
+        files_dir: config.tools_dir,
+    };
+
+    let titles = Titles {
+        pool: pool.clone(),
+        chat_backends: chat_backends.clone(),
+        limits: limits.clone(),
+        model: config.title_model,
+    };
+
+    let generations = Arc::new(Mutex::new(HashMap::new()));
+
+    let queue = GenerationQueue {
+        pool: pool.clone(),
+        chat_backends: chat_backends.clone(),
+        limits,
+        generations: generations.clone(),
+        tools: tools.clone(),
+        titles: titles.clone(),
+    };
+
+    let state = Arc::new(AppState {
+        pool,
+        http_client,
+        chat_backends,
+        generations,
+        unreachable_backends,
+        tools,
+        queue,
+        pulls: Pulls::default(),
+    });
+
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", config.port)).await?;
+    axum::serve(listener, app(state)).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::ChatResponse;
+    use futures::future::BoxFuture;
+    use futures::stream::BoxStream;
+    use serde_json::json;
+
+    #[derive(Debug, Default)]
+    struct FakeBackend {
+        scripts: std::sync::Mutex<VecDeque<Vec<ChatResponse>>>,
+        requests: std::sync::Mutex<Vec<serde_json::Value>>,
+    }
+
+    impl FakeBackend {
+        fn new(scripts: impl IntoIterator<Item = Vec<ChatResponse>>) -> Self {
+            FakeBackend {
+                scripts: std::sync::Mutex::new(scripts.into_iter().collect()),
+                requests: std::sync::Mutex::default(),
+            }
+        }
+    }
+
+    impl ChatBackend for FakeBackend {
+        fn models(&self) -> BoxFuture<'_, anyhow::Result<Vec<backend::BackendModel>>> {
+            Box::pin(async { Ok(vec![]) })
+        }
+
+        fn chat<'a>(
+            &'a self,
+            request: &'a ChatRequest,
+        ) -> BoxFuture<'a, anyhow::Result<BoxStream<'static, anyhow::Result<ChatResponse>>>>
+        {
+            Box::pin(async move {
+                self.requests
+                    .lock()
+                    .unwrap()
+                    .push(serde_json::to_value(request)?);
+
+                let script = self.scripts.lock().unwrap().pop_front();
+
+                // out of script, it never says anything
+                let chunks: BoxStream<'static, anyhow::Result<ChatResponse>> = match script {
+                    Some(chunks) => Box::pin(futures::stream::iter(chunks.into_iter().map(Ok))),
+                    None => Box::pin(futures::stream::pending()),
+                };
+
+                Ok(chunks)
+            })
+        }
+    }
+
+    fn chunk(content: &str) -> ChatResponse {
+        serde_json::from_value(json!({ "message": { "content": content }, "done": false })).unwrap()
+    }
+
+    fn done() -> ChatResponse {
+        serde_json::from_value(json!({ "message": { "content": "" }, "done": true })).unwrap()
+    }
+
+    fn tool_call(name: &str, arguments: serde_json::Value) -> ChatResponse {
+        serde_json::from_value(json!({
+            "message": {
+                "content": "",
+                "tool_calls": [{ "function": { "name": name, "arguments": arguments } }],
+            },
+            "done": false,
+        }))
+        .unwrap()
+    }
+
+    fn chat_request(format: Option<serde_json::Value>) -> ChatRequest {
+        ChatRequest {
+            model: "fake".to_string(),
+            messages: vec![ChatRequestMessage::text("user", "hi".to_string())],
+            options: GenerationOptions::default(),
+            keep_alive: None,
+            tools: vec![],
+            format,
+        }
+    }
+
+    async fn test_pool() -> sqlx::Pool<Sqlite> {
+        // every connection to `:memory:` is its own database
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+
+        migrate(&mut pool.acquire().await.unwrap()).await.unwrap();
+
+        pool
+    }
+
+    async fn test_reply(pool: &sqlx::Pool<Sqlite>) -> i64 {
+        sqlx::query(
+            "
+            insert into backends (name, url) values ('default', 'http://localhost:11434');
+            insert into models (name, backend_id) values ('fake', 1);
+            insert into conversations (name, model_id) values ('test', 1);
+            ",
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+
+        sqlx::query_scalar(
+            "insert into messages (body, who, conversation_id) values ('', ?, 1) returning id",
+        )
+        .bind(Who::Llama)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    fn sent(
+        ollama_rx: &mut broadcast::Receiver<OllamaResponseMessage>,
+    ) -> Vec<OllamaResponseMessage> {
+        std::iter::from_fn(|| ollama_rx.try_recv().ok()).collect()
+    }
+
+    fn format_check(chat_chunks: &[OllamaResponseMessage]) -> Option<Result<(), String>> {
+        match chat_chunks.last() {
+            Some(OllamaResponseMessage::Done { format_check, .. }) => format_check.clone(),
+            other => panic!("the reply didn't finish: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn think_tags_split_across_chunks_are_kept_out_of_the_reply() {
+        let backend = FakeBackend::new([vec![
+            chunk("<thi"),
+            chunk("nk>let me see</th"),
+            chunk("ink>the answer"),
+            chunk(" is <b>"),
+            done(),
+        ]]);
+
+        let (ollama_tx, mut ollama_rx) = GenerationTx::new();
+
+        let tool_calls = stream_chat_response(&backend, &chat_request(None), &ollama_tx)
+            .await
+            .unwrap();
+
+        assert!(tool_calls.is_empty());
+        assert_eq!(
+            ollama_tx.text(),
+            ("the answer is <b>".to_string(), "let me see".to_string())
+        );
+        assert_eq!(format_check(&sent(&mut ollama_rx)), None);
+    }
+
+    #[tokio::test]
+    async fn replies_asked_to_be_json_keep_their_think_tags() {
+        let backend = FakeBackend::new([vec![chunk("<think>{}</think>"), done()]]);
+
+        let (ollama_tx, mut ollama_rx) = GenerationTx::new();
+
+        stream_chat_response(&backend, &chat_request(Some(json!("json"))), &ollama_tx)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            ollama_tx.text(),
+            ("<think>{}</think>".to_string(), String::new())
+        );
+        assert!(format_check(&sent(&mut ollama_rx)).unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn replies_are_checked_against_their_schema() {
+        let schema = json!({
+            "type": "object",
+            "properties": { "n": { "type": "integer" } },
+            "required": ["n"],
+        });
+
+        for (reply, valid) in [(r#"{"n": 1}"#, true), (r#"{"n": "one"}"#, false)] {
+            let backend = FakeBackend::new([vec![chunk(reply), done()]]);
+
+            let (ollama_tx, mut ollama_rx) = GenerationTx::new();
+
+            stream_chat_response(&backend, &chat_request(Some(schema.clone())), &ollama_tx)
+                .await
+                .unwrap();
+
+            let format_check = format_check(&sent(&mut ollama_rx)).unwrap();
+
+            assert_eq!(format_check.is_ok(), valid, "{reply}: {format_check:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn replies_that_stop_before_they_are_done_are_errors() {
+        let backend = FakeBackend::new([vec![chunk("half a <thi")]]);
 
-    txn.commit().await.map_err(|e| e.to_string())?;
+        let (ollama_tx, _ollama_rx) = GenerationTx::new();
+
+        assert!(
+            stream_chat_response(&backend, &chat_request(None), &ollama_tx)
+                .await
+                .is_err()
+        );
+
+        // what looked like it could be the start of a tag isn't lost
+        assert_eq!(ollama_tx.text(), ("half a <thi".to_string(), String::new()));
+    }
+
+    #[tokio::test]
+    async fn tool_results_are_sent_back_until_the_model_answers() {
+        let pool = test_pool().await;
+
+        let tools = Tools {
+            pool: pool.clone(),
+            history_pool: pool.clone(),
+            files_dir: None,
+        };
+
+        let reply_id = test_reply(&pool).await;
 
-    spawn_llm_response_update_task(conn2, ollama_response.id, ollama_rx2);
+        // JSON written across both rounds, which is only valid as a whole
+        let backend = FakeBackend::new([
+            vec![
+                chunk(r#"{"answer": "#),
+                tool_call("calculator", json!({ "expression": "6*7" })),
+                done(),
+            ],
+            vec![chunk("42}"), done()],
+        ]);
 
-    send_chat_message(http_client, &messages, model.name, ollama_tx)
+        let (ollama_tx, mut ollama_rx) = GenerationTx::new();
+
+        stream_chat_response_with_tools(
+            &backend,
+            chat_request(Some(json!("json"))),
+            reply_id,
+            &ollama_tx,
+            &tools,
+        )
         .await
-        .map_err(|e| e.to_string())?;
+        .unwrap();
 
-    let count = messages.len();
+        let requests = backend.requests.lock().unwrap().clone();
 
-    Ok(html! {
-            tr {
-                td {
-                    (count)
-                }
-                td {
-                    (message.inserted_at)
-                }
-                td {
-                    (message.who.to_string())
-                }
-                td {
-                    pre {
-                        (message.body)
-                    }
-                }
-                td {
-                    a hx-post=(format!("/conversations/{}/fork/{}", message.conversation_id, message.id)) {
-                        "Fork"
-                    }
-                }
-            }
-            tr {
-                td {
-                    (count + 1)
-                }
-                td {
-                    (ollama_response.inserted_at)
-                }
-                td {
-                    (Who::Llama)
-                }
-                td {
-                    // TODO
-                    // document what this whole thing does...
-                    div
-                        id="sse-listener"
-                        hx-ext="sse"
-                        sse-connect="/messages/response/sse"
-                        sse-swap="ChatData"
-                        hx-target="next"
-                        hx-swap="beforeend"
-                    {
-                        div
-                        hx-get="/empty"
-                        hx-trigger="sse:ChatDone"
-                        hx-target="#sse-listener"
-                        hx-swap="delete" {}
-                    }
-                    // javascript removes this id when the llm is done responding
-                    pre id="llm-response" {
-                        (FILLED_BLOCK)
-                    }
-                }
-                td {
-                    a hx-post=(format!("/conversations/{}/fork/{}", ollama_response.conversation_id, ollama_response.id)) {
-                        "Fork"
-                    }
-                }
-            }
-    })
-}
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[1]["messages"][1]["tool_calls"][0]["function"]["name"],
+            "calculator"
+        );
+        assert_eq!(requests[1]["messages"][1]["content"], r#"{"answer": "#);
+        assert_eq!(requests[1]["messages"][2]["role"], "tool");
+        assert_eq!(requests[1]["messages"][2]["content"], "42");
 
-fn spawn_llm_response_update_task(
-    mut conn: PoolConnection<Sqlite>,
-    ollama_response_message_id: i64,
-    mut ollama_rx: broadcast::Receiver<OllamaResponseMessage>,
-) {
-    tokio::spawn(async move {
-        while let Ok(chat_chunk) = ollama_rx.recv().await {
-            match chat_chunk {
-                OllamaResponseMessage::More { response } => {
-                    sqlx::query(
-                        "
-                        update messages
-                        set body = body || ?
-                         where id = ?
-                         ",
-                    )
-                    .bind(response)
-                    .bind(ollama_response_message_id)
-                    .execute(&mut *conn)
-                    .await
-                    .map_err(|e| e.to_string())
-                    // TODO add some error channel here instead of unwrapping
-                    .unwrap();
-                }
-                OllamaResponseMessage::Done => break,
-            }
-        }
-    });
-}
+        assert_eq!(ollama_tx.text().0, r#"{"answer": 42}"#);
 
-async fn messages_create_sse_handler(
-    State(state): State<Arc<Mutex<AppState>>>,
-) -> axum::response::Result<Sse<impl Stream<Item = Result<Event, Infallible>>>> {
-    let state = state.lock().await;
+        let chat_chunks = sent(&mut ollama_rx);
 
-    let ollama_rx = state.ollama_rx.resubscribe();
+        assert!(chat_chunks.iter().any(|chat_chunk| matches!(
+            chat_chunk,
+            OllamaResponseMessage::ToolCalls { messages } if messages.len() == 2
+        )));
+        assert_eq!(format_check(&chat_chunks), Some(Ok(())));
 
-    let sse_stream = tokio_stream::wrappers::BroadcastStream::new(ollama_rx)
-        .map(|chat_chunk| {
-            let chat_chunk = chat_chunk.unwrap();
-            match chat_chunk {
-                OllamaResponseMessage::More { mut response } => {
-                    response.push(FILLED_BLOCK);
-                    Event::default().event("ChatData").data(response)
-                }
-                OllamaResponseMessage::Done => {
-                    debug!("Sending 'Done' SSE message");
-                    Event::default().event("ChatDone").data("")
-                }
-            }
-        })
-        .map(Ok);
+        let tool_messages: Vec<(Who, Option<i64>)> =
+            sqlx::query_as("select who, reply_id from messages where id != ? order by id")
+                .bind(reply_id)
+                .fetch_all(&pool)
+                .await
+                .unwrap();
 
-    Ok(Sse::new(sse_stream).keep_alive(
-        axum::response::sse::KeepAlive::new()
-            .interval(std::time::Duration::from_secs(1))
-            .text("keep-alive-text"),
-    ))
-}
+        assert_eq!(
+            tool_messages,
+            [
+                (Who::ToolCall, Some(reply_id)),
+                (Who::ToolResult, Some(reply_id))
+            ]
+        );
+    }
 
-async fn conversations_create(
-    State(state): State<Arc<Mutex<AppState>>>,
-) -> axum::response::Result<HeaderMap> {
-    let state = state.lock().await;
-    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+    #[tokio::test]
+    async fn models_that_keep_calling_tools_are_given_up_on() {
+        let pool = test_pool().await;
 
-    let conversation_id: (i64,) = sqlx::query_as(
-        "insert into conversations (name) values ('a new conversation') returning id;",
-    )
-    .fetch_one(&mut *conn)
-    .await
-    .map_err(|e| e.to_string())?;
+        let tools = Tools {
+            pool: pool.clone(),
+            history_pool: pool.clone(),
+            files_dir: None,
+        };
 
-    let conversation_id = conversation_id.0;
+        let reply_id = test_reply(&pool).await;
 
-    let path = format!("/conversations/{conversation_id}");
+        let backend = FakeBackend::new(
+            std::iter::repeat_with(|| vec![tool_call("get_time", json!({})), done()])
+                .take(MAX_TOOL_ROUNDS),
+        );
 
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        "HX-Redirect",
-        HeaderValue::try_from(path).map_err(|e| e.to_string())?,
-    );
+        let (ollama_tx, _ollama_rx) = GenerationTx::new();
 
-    Ok(headers)
-}
+        let result = stream_chat_response_with_tools(
+            &backend,
+            chat_request(None),
+            reply_id,
+            &ollama_tx,
+            &tools,
+        )
+        .await;
 
-async fn conversations_fork_create(
-    State(state): State<Arc<Mutex<AppState>>>,
-    Path((conversation_id, message_id)): Path<(i64, i64)>,
-) -> axum::response::Result<HeaderMap> {
-    let state = state.lock().await;
-    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
-    let mut tx = conn.begin().await.map_err(|e| e.to_string())?;
+        assert!(result.is_err());
+        assert_eq!(backend.requests.lock().unwrap().len(), MAX_TOOL_ROUNDS);
+    }
 
-    let (new_conversation_id,): (i64,) = sqlx::query_as(
-        "
-        insert into conversations (name, source_conversation_id)
-        values ('a new conversation', ?)
-        returning id;",
-    )
-    .bind(conversation_id)
-    .fetch_one(&mut *tx)
-    .await
-    .map_err(|e| e.to_string())?;
+    #[tokio::test]
+    async fn history_queries_are_single_selects_that_stop_when_they_run_too_long() {
+        let pool = test_pool().await;
 
-    let latest_message: Message = sqlx::query_as(
-        "
-    select
-        id,
-        body,
-        who,
-        conversation_id,
-        inserted_at
-    from messages
-    where id = ?
-    limit 1;
-    ",
-    )
-    .bind(message_id)
-    .fetch_one(&mut *tx)
-    .await
-    .map_err(|e| e.to_string())?;
+        let tools = Tools {
+            pool: pool.clone(),
+            history_pool: pool.clone(),
+            files_dir: None,
+        };
 
-    sqlx::query(
-        "
-    insert into messages (who, body, conversation_id)
-    select
-        who,
-        body,
-        ?
-    from messages
-    where conversation_id = ?
-    and messages.inserted_at <= ?
-    and messages.id <= ?
-    ",
-    )
-    .bind(new_conversation_id)
-    .bind(latest_message.conversation_id)
-    .bind(latest_message.inserted_at)
-    .bind(message_id)
-    .execute(&mut *tx)
-    .await
-    .map_err(|e| e.to_string())?;
+        let timeout = std::time::Duration::from_millis(100);
 
-    tx.commit().await.map_err(|e| e.to_string())?;
+        for sql in [
+            "select 1; delete from messages",
+            "delete from messages",
+            "pragma table_info(messages)",
+        ] {
+            assert!(tools.query_history(sql, timeout).await.is_err(), "{sql}");
+        }
 
-    let path = format!("/conversations/{new_conversation_id}");
+        // endless, but only as many rows as are shown are read
+        let rows = tools
+            .query_history(
+                "with recursive n(i) as (select 1 union all select i + 1 from n) select i from n",
+                timeout,
+            )
+            .await
+            .unwrap();
 
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        "HX-Redirect",
-        HeaderValue::try_from(path).map_err(|e| e.to_string())?,
-    );
+        assert!(rows.ends_with(&format!(
+            "(there are more rows than the {MAX_TOOL_QUERY_ROWS} shown)"
+        )));
 
-    Ok(headers)
-}
+        let error = tools
+            .query_history(
+                "with recursive n(i) as (select 1 union all select i + 1 from n) select count(*) from n;",
+                timeout,
+            )
+            .await
+            .unwrap_err();
 
-#[derive(Deserialize)]
-struct ConversationNameChangeForm {
-    conversation_name: String,
-}
+        assert_eq!(error.to_string(), "the query took too long");
 
-async fn conversations_edit_get(
-    Path(conversation_id): Path<i64>,
-) -> axum::response::Result<Markup> {
-    Ok(html! {
-        div
-            id="conversation-name-edit"
-            class="level-item"
-        {
-            form
-                hx-put=(format!("/conversations/{conversation_id}/edit"))
-                hx-target="#conversation-name-block"
-                hx-swap="outerHTML"
-            {
-                div class="field is-horizontal" {
-                    div class="field-body" {
-                        div class="field" {
-                            div class="control" {
-                                input type="text" name="conversation_name" class="input" placeholder="Conversation name" required;
-                            }
-                        }
-                        div class="field" {
-                            p class="control" {
-                                button class="button is-link" {
-                                    "Submit"
-                                }
-                            }
-                        }
-                        div class="field" {
-                            p class="control" {
-                                button
-                                    hx-get=(format!("/conversations/{}/edit/cancel", conversation_id))
-                                    hx-target="#conversation-name-edit"
-                                    hx-swap="outerHTML"
-                                    class="button is-link"
-                                {
-                                    "Cancel"
-                                }
-                            }
-                        }
-                    }
-                }
-            }
+        // the connection is free again, and isn't stopped by the last query's deadline
+        assert_eq!(
+            tools
+                .query_history("select 1 as one", timeout)
+                .await
+                .unwrap(),
+            r#"[{"one":1}]"#
+        );
+    }
+
+    #[tokio::test]
+    async fn backends_without_a_configured_limit_get_the_default() {
+        use futures::FutureExt;
+
+        let limits = Limits::new([(1, 1)]);
+
+        let _permit = limits.acquire(1).await.unwrap();
+        assert!(limits.acquire(1).now_or_never().is_none());
+
+        let mut permits = vec![];
+
+        for _ in 0..DEFAULT_CONCURRENCY {
+            permits.push(limits.acquire(2).await.unwrap());
         }
-    })
-}
 
-async fn conversations_edit_save(
-    State(state): State<Arc<Mutex<AppState>>>,
-    Path(conversation_id): Path<i64>,
-    Form(name_change_form): Form<ConversationNameChangeForm>,
-) -> axum::response::Result<Markup> {
-    let state = state.lock().await;
-    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+        assert!(limits.acquire(2).now_or_never().is_none());
+    }
 
-    sqlx::query(
-        "
-    update conversations
-    set name = ?
-    where id = ?",
-    )
-    .bind(&name_change_form.conversation_name)
-    .bind(conversation_id)
-    .execute(&mut *conn)
-    .await
-    .map_err(|e| e.to_string())?;
+    #[tokio::test]
+    async fn conversations_are_titled_from_their_first_exchange_only_once() {
+        let pool = test_pool().await;
+
+        let reply_id = test_reply(&pool).await;
+
+        sqlx::query(
+            "
+            update conversations set name = ?;
+            update messages set status = ?, inserted_at = '2026-01-01 00:00:01';
+            ",
+        )
+        .bind(DEFAULT_CONVERSATION_NAME)
+        .bind(MessageStatus::Failed)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        for (who, body, inserted_at) in [
+            (Who::Me, "first", "2026-01-01 00:00:00"),
+            (Who::Me, "second", "2026-01-01 00:00:02"),
+            (Who::Llama, "answer", "2026-01-01 00:00:03"),
+        ] {
+            sqlx::query(
+                "insert into messages (who, body, conversation_id, status, inserted_at) values (?, ?, 1, ?, ?)",
+            )
+            .bind(who)
+            .bind(body)
+            .bind(MessageStatus::Done)
+            .bind(inserted_at)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        // a title that comes back empty
+        let backend = Arc::new(FakeBackend::new([vec![chunk("\"\""), done()]]));
+
+        let chat_backend: Arc<dyn ChatBackend> = backend.clone();
+
+        let titles = Titles {
+            pool: pool.clone(),
+            chat_backends: Arc::new(HashMap::from([(1, chat_backend)])),
+            limits: Limits::default(),
+            model: None,
+        };
+
+        titles.write(reply_id).await.unwrap();
+        titles.write(reply_id).await.unwrap();
+
+        let requests = backend.requests.lock().unwrap().clone();
+
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            requests[0]["messages"][1]["content"],
+            "user: first\n\nassistant: answer\n\n"
+        );
+
+        let (name,): (String,) = sqlx::query_as("select name from conversations")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(name, DEFAULT_CONVERSATION_NAME);
+    }
+
+    fn test_state(pool: sqlx::Pool<Sqlite>, chat_backend: Arc<dyn ChatBackend>) -> Arc<AppState> {
+        let chat_backends: ChatBackends = Arc::new(HashMap::from([(1, chat_backend)]));
+
+        let generations: Generations = Arc::default();
+
+        let tools = Tools {
+            pool: pool.clone(),
+            history_pool: pool.clone(),
+            files_dir: None,
+        };
+
+        let limits = Limits::default();
+
+        let queue = GenerationQueue {
+            pool: pool.clone(),
+            chat_backends: chat_backends.clone(),
+            limits: limits.clone(),
+            generations: generations.clone(),
+            tools: tools.clone(),
+            titles: Titles {
+                pool: pool.clone(),
+                chat_backends: chat_backends.clone(),
+                limits,
+                model: None,
+            },
+        };
+
+        Arc::new(AppState {
+            pool,
+            http_client: reqwest::Client::new(),
+            chat_backends,
+            generations,
+            unreachable_backends: Arc::default(),
+            tools,
+            queue,
+            pulls: Pulls::default(),
+        })
+    }
 
-    Ok(html! {
-        div
-            id="conversation-name-block"
-            class="level-left"
-        {
-            div class="level-item" {
-                h1 class="title" {
-                    (name_change_form.conversation_name)
-                }
-            }
-            div class="level-item" {
-                a hx-get=(format!("/conversations/{}/edit", conversation_id)) {
-                    "Edit"
-                }
+    fn tool_message(id: i64) -> (usize, Message) {
+        let message = Message {
+            id,
+            body: "{}".to_string(),
+            thinking: String::new(),
+            who: Who::ToolCall,
+            conversation_id: 1,
+            inserted_at: String::new(),
+            status: MessageStatus::Done,
+            error: None,
+            version_of: None,
+            version_number: 0,
+            version_count: 0,
+            attachment_ids: None,
+            tool_name: Some("get_time".to_string()),
+            reply_id: Some(1),
+            reply_first_version_id: Some(1),
+            json_valid: None,
+            json_error: None,
+            pinned: false,
+            context_cut_before: None,
+            context_summary: None,
+            metadata: GenerationMetadata::default(),
+        };
+
+        (id as usize, message)
+    }
+
+    fn tool_message_ids(chat_chunk: &OllamaResponseMessage) -> Vec<i64> {
+        match chat_chunk {
+            OllamaResponseMessage::ToolCalls { messages } => {
+                messages.iter().map(|(_, message)| message.id).collect()
             }
+            other => panic!("expected tool calls, got {other:?}"),
         }
-    })
-}
+    }
 
-async fn conversations_edit_cancel(
-    State(_state): State<Arc<Mutex<AppState>>>,
-    Path(conversation_id): Path<i64>,
-) -> axum::response::Result<Markup> {
-    Ok(html! {
-        div
-            id="conversation-name-edit"
-            class="level-item"
-        {
-            a
-                hx-get=(format!("/conversations/{}/edit", conversation_id))
-                hx-swap="outerHTML"
-                hx-target="#conversation-name-edit"
-            {
-                "Edit"
-            }
+    #[test]
+    fn reply_offsets_are_read_back_from_their_event_ids() {
+        let offset = ReplyOffset {
+            body: 12,
+            thinking: 3,
+            tool_message: 40,
+        };
+
+        let parsed: ReplyOffset = offset.to_string().parse().unwrap();
+
+        assert_eq!(
+            (parsed.body, parsed.thinking, parsed.tool_message),
+            (12, 3, 40)
+        );
+
+        for malformed in [
+            "",
+            "12",
+            "12.3",
+            "12.3.40.1",
+            "a.b.c",
+            "-1.0.0",
+            "1.2.x",
+            "1..3",
+        ] {
+            assert!(
+                malformed.parse::<ReplyOffset>().is_err(),
+                "`{malformed}` parsed"
+            );
         }
-    })
-}
+    }
 
-async fn conversations_delete(
-    State(state): State<Arc<Mutex<AppState>>>,
-    Path(conversation_id): Path<i64>,
-) -> axum::response::Result<HeaderMap> {
-    let state = state.lock().await;
-    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+    #[test]
+    fn reply_offsets_advance_by_bytes() {
+        let mut offset = ReplyOffset::default();
 
-    sqlx::query(
-        "
-    delete from conversations
-    where id = ?;",
-    )
-    .bind(conversation_id)
-    .execute(&mut *conn)
-    .await
-    .map_err(|e| e.to_string())?;
+        offset.advance(OllamaResponseMessage::More {
+            response: "héllo".to_string(),
+        });
+        offset.advance(OllamaResponseMessage::Thinking {
+            thinking: "日本".to_string(),
+        });
+        offset.advance(OllamaResponseMessage::Stopped);
 
-    let path = "/conversations/";
+        assert_eq!((offset.body, offset.thinking), (6, 6));
+    }
 
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        "HX-Redirect",
-        HeaderValue::try_from(path).map_err(|e| e.to_string())?,
-    );
+    #[test]
+    fn tool_messages_already_shown_are_not_shown_again() {
+        let mut offset = ReplyOffset {
+            tool_message: 4,
+            ..Default::default()
+        };
 
-    Ok(headers)
-}
+        let replayed = OllamaResponseMessage::ToolCalls {
+            messages: vec![tool_message(3), tool_message(4)],
+        };
 
-#[derive(Deserialize)]
-struct ModelSelection {
-    #[serde(rename(deserialize = "model-id"))]
-    model_id: i64,
-}
+        assert!(offset.advance(replayed).is_none());
+        assert_eq!(offset.tool_message, 4);
 
-async fn select_model(
-    State(state): State<Arc<Mutex<AppState>>>,
-    Path(conversation_id): Path<i64>,
-    Form(model_selection): Form<ModelSelection>,
-) -> axum::response::Result<()> {
-    let state = state.lock().await;
+        let partly_new = OllamaResponseMessage::ToolCalls {
+            messages: vec![
+                tool_message(3),
+                tool_message(4),
+                tool_message(5),
+                tool_message(6),
+            ],
+        };
 
-    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+        assert_eq!(
+            tool_message_ids(&offset.advance(partly_new).unwrap()),
+            [5, 6]
+        );
+        assert_eq!(offset.tool_message, 6);
+    }
 
-    sqlx::query(
-        "
-    update conversations
-    set model_id = ?
-    where id = ?
-    ",
-    )
-    .bind(model_selection.model_id)
-    .bind(conversation_id)
-    .execute(&mut *conn)
-    .await
-    .map_err(|e| e.to_string())?;
+    #[test]
+    fn catching_up_skips_what_was_seen() {
+        let written = Written {
+            body: "héllo wörld".to_string(),
+            thinking: "hmm".to_string(),
+            tool_messages: vec![tool_message(3), tool_message(4)],
+            end: None,
+        };
 
-    Ok(())
-}
+        let mut offset = ReplyOffset {
+            body: 3,
+            thinking: 3,
+            tool_message: 4,
+        };
 
-#[derive(Debug)]
-struct AppState {
-    pool: sqlx::Pool<Sqlite>,
-    http_client: reqwest::Client,
-    ollama_tx: broadcast::Sender<OllamaResponseMessage>,
-    ollama_rx: broadcast::Receiver<OllamaResponseMessage>,
-}
+        let caught_up: Vec<OllamaResponseMessage> = written
+            .since(offset)
+            .into_iter()
+            .filter_map(|chat_chunk| offset.advance(chat_chunk))
+            .collect();
 
-#[derive(Clone, sqlx::Type, Deserialize, Serialize)]
-enum Who {
-    #[sqlx(rename = "Me")]
-    Me,
-    #[sqlx(rename = "LlaMA")]
-    Llama,
-}
+        let [OllamaResponseMessage::More { response }] = caught_up.as_slice() else {
+            panic!("expected only the rest of the body, got {caught_up:?}");
+        };
 
-impl Display for Who {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Who::Me => write!(f, "Me"),
-            Who::Llama => write!(f, "LlaMA"),
-        }
+        assert_eq!(response, "llo wörld");
+        assert_eq!(offset.body, written.body.len());
     }
-}
 
-#[derive(Debug, Parser)]
-struct Config {
-    #[arg(long, env, default_value = "conversations.db")]
-    database: String,
-    #[arg(long, env, default_value = "3000")]
-    port: u16,
-}
+    #[test]
+    fn offsets_a_reply_never_reached_are_not_trusted() {
+        let written = Written {
+            body: "héllo".to_string(),
+            thinking: "hmm".to_string(),
+            ..Default::default()
+        };
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .init();
+        let reached = |body, thinking| {
+            written.has_reached(ReplyOffset {
+                body,
+                thinking,
+                tool_message: 0,
+            })
+        };
 
-    let config = Config::parse();
+        assert!(reached(0, 0));
+        assert!(reached(3, 3));
+        assert!(reached(6, 3));
+        // partway through the é
+        assert!(!reached(2, 0));
+        // from before a retry, when the reply was longer
+        assert!(!reached(7, 0));
+        assert!(!reached(0, 4));
+    }
 
-    let opts =
-        sqlx::sqlite::SqliteConnectOptions::from_str(&format!("sqlite://{}", config.database))?
-            .busy_timeout(std::time::Duration::from_secs(5))
-            .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal)
-            .create_if_missing(true)
-            .foreign_keys(true);
+    async fn sse_events(
+        state: Arc<AppState>,
+        message_id: i64,
+        offset: ReplyOffset,
+        last_event_id: Option<&str>,
+    ) -> Vec<String> {
+        let mut headers = HeaderMap::new();
 
-    let pool = sqlx::SqlitePool::connect_with(opts).await?;
+        if let Some(last_event_id) = last_event_id {
+            headers.insert("Last-Event-ID", last_event_id.parse().unwrap());
+        }
 
-    let mut connection = pool.acquire().await?;
+        let response = messages_sse(State(state), Path(message_id), Query(offset), headers)
+            .await
+            .unwrap();
 
-    let mut txn = connection.begin().await?;
+        let mut body = response.into_body().into_data_stream();
 
-    sqlx::query(
-        "create table if not exists models (
-            id integer primary key autoincrement not null,
-            name text not null,
-            inserted_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
-            updated_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'))
-        );
-        ",
-    )
-    .execute(&mut *txn)
-    .await?;
+        let mut events = vec![];
 
-    sqlx::query("create unique index if not exists models_name on models (name);")
-        .execute(&mut *txn)
-        .await?;
+        while let Some(frame) = tokio::time::timeout(std::time::Duration::from_secs(5), body.next())
+            .await
+            .expect("the reply never ended")
+        {
+            let event = String::from_utf8(frame.unwrap().to_vec()).unwrap();
+            let done = event.contains("event: ChatDone");
 
-    sqlx::query(
-        "create table if not exists conversations (
-            id integer primary key autoincrement not null,
-            name text not null,
-            model_id integer not null default 1,
-            source_conversation_id integer,
-            inserted_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
-            updated_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+            events.push(event);
 
-            foreign key(model_id) references models(id)
-        );",
-    )
-    .execute(&mut *txn)
-    .await?;
+            if done {
+                break;
+            }
+        }
 
-    sqlx::query(
-        "create table if not exists messages (
-            id integer primary key autoincrement not null,
-            body text not null,
-            who text not null,
-            conversation_id integer not null,
-            inserted_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
-            updated_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+        events
+    }
 
-            foreign key(conversation_id) references conversations(id) on delete cascade
-        )",
-    )
-    .execute(&mut *txn)
-    .await?;
+    #[tokio::test]
+    async fn replies_that_finished_before_anyone_watched_are_sent_from_the_database() {
+        let pool = test_pool().await;
 
-    txn.commit().await?;
+        let reply_id = test_reply(&pool).await;
 
-    let (ollama_tx, ollama_rx) = broadcast::channel(10);
+        sqlx::query("update messages set body = 'héllo wörld', status = ? where id = ?")
+            .bind(MessageStatus::Done)
+            .bind(reply_id)
+            .execute(&pool)
+            .await
+            .unwrap();
 
-    let http_client = reqwest::Client::new();
+        let state = test_state(pool, Arc::new(FakeBackend::default()));
 
-    let available_models = get_available_models(http_client.clone()).await.unwrap();
+        let body_after = |events: &[String]| -> String {
+            events
+                .iter()
+                .filter(|event| event.contains("event: ChatData"))
+                .filter_map(|event| event.lines().find_map(|line| line.strip_prefix("data: ")))
+                .collect()
+        };
 
-    let mut txn = connection.begin().await?;
+        for (last_event_id, rest) in [
+            // where the browser got to
+            (Some("3.0.0"), "llo wörld"),
+            // it can't have got partway through the é, or past the end
+            (Some("2.0.0"), "éllo wörld"),
+            (Some("100.0.0"), "éllo wörld"),
+            (Some("not an offset"), "éllo wörld"),
+            (None, "éllo wörld"),
+        ] {
+            let events = sse_events(
+                state.clone(),
+                reply_id,
+                ReplyOffset {
+                    body: 1,
+                    ..Default::default()
+                },
+                last_event_id,
+            )
+            .await;
 
-    for model in available_models {
-        sqlx::query(
-            "
-        insert into models
-        (name) values (?)
-        on conflict do nothing;
-        ",
-        )
-        .bind(model)
-        .execute(&mut *txn)
-        .await?;
+            assert_eq!(
+                body_after(&events),
+                format!("{rest}{FILLED_BLOCK}"),
+                "{last_event_id:?}"
+            );
+            assert!(events.last().unwrap().contains("event: ChatDone"));
+        }
     }
 
-    txn.commit().await?;
+    #[tokio::test]
+    async fn replies_that_finished_before_their_channel_was_watched_are_sent_in_full() {
+        let (ollama_tx, _ollama_rx) = GenerationTx::new();
 
-    let state = Arc::new(Mutex::new(AppState {
-        pool,
-        http_client,
-        ollama_tx,
-        ollama_rx,
-    }));
+        ollama_tx.send(OllamaResponseMessage::More {
+            response: "hello".to_string(),
+        });
+        ollama_tx.send(OllamaResponseMessage::Done {
+            metadata: GenerationMetadata::default(),
+            format_check: None,
+        });
 
-    let app = Router::new()
-        .route("/", get(conversations_index))
-        .route("/conversations/", get(conversations_index))
-        .route("/conversations/{id}", get(conversations_show))
-        .route("/conversations/{id}/edit", get(conversations_edit_get))
-        .route("/conversations/{id}/edit", put(conversations_edit_save))
-        .route(
-            "/conversations/{id}/edit/cancel",
-            get(conversations_edit_cancel),
-        )
-        .route("/conversations/{id}/delete", delete(conversations_delete))
-        .route(
-            "/conversations/{conversation_id}/fork/{message_id}",
-            post(conversations_fork_create),
-        )
-        .route("/conversations/new", post(conversations_create))
-        .route("/messages/new", post(messages_create))
-        .route("/messages/response/sse", get(messages_create_sse_handler))
-        .route("/empty", get(|| async {}))
-        .route("/models/select/{conversation_id}", put(select_model))
-        .route(
-            "/dev/state",
-            get(|State(state): State<Arc<Mutex<AppState>>>| async move {
-                let state = state.lock().await;
-                format!("{:#?}", state)
-            }),
+        let chat_chunks: Vec<OllamaResponseMessage> = ollama_tx
+            .watch(ReplyOffset {
+                body: 2,
+                ..Default::default()
+            })
+            .take(2)
+            .collect()
+            .await;
+
+        assert!(matches!(
+            chat_chunks.as_slice(),
+            [
+                OllamaResponseMessage::More { response },
+                OllamaResponseMessage::Done { .. }
+            ] if response == "llo"
+        ));
+    }
+
+    #[derive(Debug)]
+    struct StalledBackend {
+        calls: tokio::sync::mpsc::UnboundedSender<&'static str>,
+    }
+
+    impl ChatBackend for StalledBackend {
+        fn models(&self) -> BoxFuture<'_, anyhow::Result<Vec<backend::BackendModel>>> {
+            let _ = self.calls.send("models");
+            Box::pin(futures::future::pending())
+        }
+
+        fn chat<'a>(
+            &'a self,
+            _request: &'a ChatRequest,
+        ) -> BoxFuture<'a, anyhow::Result<BoxStream<'static, anyhow::Result<ChatResponse>>>>
+        {
+            let _ = self.calls.send("chat");
+            Box::pin(futures::future::pending())
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn page_views_do_not_wait_on_a_stalled_backend_or_each_other() {
+        const PAGE_VIEWS: u32 = 20;
+
+        // a file, so page views can each have their own connection
+        let dir = tempfile::tempdir().unwrap();
+
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(PAGE_VIEWS + 2)
+            .connect_with(
+                sqlx::sqlite::SqliteConnectOptions::new()
+                    .filename(dir.path().join("ochat.db"))
+                    .create_if_missing(true)
+                    .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal),
+            )
+            .await
+            .unwrap();
+
+        migrate(&mut pool.acquire().await.unwrap()).await.unwrap();
+
+        let reply_id = test_reply(&pool).await;
+
+        let (calls_tx, mut calls) = tokio::sync::mpsc::unbounded_channel();
+
+        let chat_backend: Arc<dyn ChatBackend> = Arc::new(StalledBackend { calls: calls_tx });
+
+        let state = test_state(pool.clone(), chat_backend.clone());
+
+        // the backend is being synced, and is writing a reply, the whole time
+        spawn_backend_sync_task(
+            pool.clone(),
+            state.chat_backends.clone(),
+            vec![Backend {
+                id: 1,
+                name: "default".to_string(),
+                url: "http://localhost:11434".to_string(),
+                kind: BackendKind::Ollama,
+            }],
+            state.unreachable_backends.clone(),
+        );
+
+        let reply = get_message(&mut pool.acquire().await.unwrap(), reply_id)
+            .await
+            .unwrap();
+
+        state.queue.push(&reply).await.unwrap();
+
+        let mut stalled = vec![calls.recv().await.unwrap(), calls.recv().await.unwrap()];
+        stalled.sort();
+        assert_eq!(stalled, ["chat", "models"]);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/conversations/{}",
+            listener.local_addr().unwrap(),
+            reply.conversation_id
+        );
+
+        tokio::spawn(axum::serve(listener, app(state)).into_future());
+
+        let http_client = reqwest::Client::new();
+
+        // the backend never answers, so page views that waited on it would never finish.
+        // the timeout is only there so that fails instead of hanging
+        let statuses = tokio::time::timeout(
+            std::time::Duration::from_secs(60),
+            futures::future::join_all(
+                (0..PAGE_VIEWS)
+                    .map(|_| async { http_client.get(&url).send().await.unwrap().status() }),
+            ),
         )
-        .with_state(state)
-        .layer(tower_http::compression::CompressionLayer::new())
-        .layer(tower_http::trace::TraceLayer::new_for_http());
+        .await
+        .expect("page views waited on the backend");
 
-    let listener = tokio::net::TcpListener::bind(("0.0.0.0", config.port)).await?;
-    axum::serve(listener, app).await?;
+        assert!(
+            statuses.iter().all(|status| status.is_success()),
+            "{statuses:?}"
+        );
 
-    Ok(())
+        pool.close().await;
+    }
 }